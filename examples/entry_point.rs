@@ -0,0 +1,19 @@
+//! A lua module built as a shared library, which can be loaded with `require "entry_point"`.
+//!
+//! Build it with `cargo build --example entry_point --features module,vendored`.
+//! This is loaded from a standalone interpreter by `tests/entry_point.rs`.
+use luao3::prelude::*;
+
+#[lua_function]
+fn greet(name: String) -> String {
+    format!("hello {name}")
+}
+
+luao3::declare_simple_module! {
+    name => entry_point,
+    members => {
+        fn greet,
+        const VERSION = 3,
+    },
+    entry_point => true,
+}
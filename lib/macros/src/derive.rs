@@ -1,2 +1,3 @@
 pub mod fromlua;
 pub mod tolua;
+pub mod userdata;
@@ -26,8 +26,13 @@ impl FromLuaField {
         } else {
             ty.clone()
         };
+        let parse_func = if crate::utils::is_callback_type(&conversion_ty) {
+            quote!(parse_callback_field)
+        } else {
+            quote!(parse_field::<#conversion_ty>)
+        };
         let primary_conversion = quote! {
-            luao3::parse_helpers::parse_field::<#conversion_ty>(
+            luao3::parse_helpers::#parse_func(
                 lua, type_name,
                 &#table_name, #key
             )?
@@ -72,9 +72,13 @@ impl ToLuaField {
 }
 
 #[derive(darling::FromDeriveInput, Debug)]
+#[darling(attributes(lua))]
 pub struct ToLuaDerive {
     ident: syn::Ident,
     data: darling::ast::Data<ToLuaVariant, ToLuaField>,
+    /// Implement `luao3::LuaEnum`, so the unit variants can be exposed as constants
+    #[darling(default)]
+    constants: darling::util::Flag,
 }
 
 #[derive(darling::FromVariant, Debug)]
@@ -168,7 +172,30 @@ pub fn expand(input: DeriveInput) -> Result<TokenStream, darling::Error> {
             }
         }
     };
+    // Unit variants can be exposed as constants
+    let enum_impl = match derive.data {
+        _ if derive.constants.is_none() => None,
+        darling::ast::Data::Enum(ref variants) => {
+            let names = variants
+                .iter()
+                .filter(|var| var.fields.is_unit())
+                .map(|var| var.ident.to_string());
+            let (enum_impl_generics, _, _) = input.generics.split_for_impl();
+            Some(quote! {
+                impl #enum_impl_generics luao3::LuaEnum for #original_name #ty_generics #where_clause {
+                    const VARIANTS: &'static [&'static str] = &[#(#names),*];
+                }
+            })
+        }
+        darling::ast::Data::Struct(_) => {
+            return Err(
+                darling::Error::custom("Only enums can have `#[lua(constants)]`")
+                    .with_span(&derive.ident),
+            );
+        }
+    };
     Ok(quote! {
+        #enum_impl
         impl #impl_generics mlua::ToLua<'lua> for #original_name #ty_generics #where_clause {
             fn to_lua(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
                 let type_name: &'static str = std::any::type_name::<#original_name #ty_generics>();
@@ -0,0 +1,118 @@
+use darling::FromDeriveInput;
+use proc_macro2::{Ident, TokenStream};
+use quote::{quote, quote_spanned};
+use syn::{spanned::Spanned, DeriveInput};
+
+use crate::metamethods;
+
+#[derive(darling::FromField, Debug)]
+#[darling(attributes(lua))]
+pub struct UserDataField {
+    ident: Option<syn::Ident>,
+    ty: syn::Type,
+    /// Generate a getter (requires `Clone`)
+    #[darling(default)]
+    get: darling::util::Flag,
+    /// Generate a setter
+    #[darling(default)]
+    set: darling::util::Flag,
+    /// Don't expose this field to lua
+    #[darling(default)]
+    skip: darling::util::Flag,
+    /// Override the name of the field in lua
+    #[darling(default)]
+    rename: Option<String>,
+}
+
+impl UserDataField {
+    fn expand(&self) -> TokenStream {
+        if self.skip.is_some() {
+            return quote!();
+        }
+        let ident = self.ident.as_ref().unwrap();
+        let ty = &self.ty;
+        let name = match self.rename {
+            Some(ref name) => quote!(#name),
+            None => quote_spanned!(ident.span() => stringify!(#ident)),
+        };
+        // Without any explicit flags, fields can be both read and written
+        let explicit = self.get.is_some() || self.set.is_some();
+        let getter = if self.get.is_some() || !explicit {
+            Some(quote_spanned! { ty.span() =>
+                fields.add_field_method_get(#name, |_, this| {
+                    Ok(<#ty as Clone>::clone(&this.#ident))
+                });
+            })
+        } else {
+            None
+        };
+        let setter = if self.set.is_some() || !explicit {
+            Some(quote_spanned! { ty.span() =>
+                fields.add_field_method_set(#name, |_, this, value: #ty| {
+                    this.#ident = value;
+                    Ok(())
+                });
+            })
+        } else {
+            None
+        };
+        quote!(#getter #setter)
+    }
+}
+
+#[derive(darling::FromDeriveInput, Debug)]
+#[darling(attributes(lua), supports(struct_named))]
+pub struct UserDataDerive {
+    ident: Ident,
+    data: darling::ast::Data<(), UserDataField>,
+    /// Include the methods from `#[lua_methods(userdata = false)]`
+    #[darling(default)]
+    methods: darling::util::Flag,
+    /// Metamethods to generate from rust traits, like `derive(Display, PartialEq)`
+    #[darling(default)]
+    derive: darling::util::PathList,
+}
+
+pub fn expand(input: DeriveInput) -> Result<TokenStream, darling::Error> {
+    let derive = UserDataDerive::from_derive_input(&input)?;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let name = &derive.ident;
+    let fields = derive
+        .data
+        .as_ref()
+        .take_struct()
+        .expect("supports(struct_named)")
+        .fields
+        .into_iter()
+        .map(UserDataField::expand);
+    let metamethods = metamethods::expand_derives(&derive.derive)?;
+    let (methods, class_name) = if derive.methods.is_some() {
+        (
+            Some(quote!(<Self as luao3::userdata::LuaMethods>::add_lua_methods(methods);)),
+            quote!(luao3::userdata::add_class_name::<Self, F>(fields);),
+        )
+    } else {
+        let name_text = name.to_string();
+        (
+            None,
+            quote! {
+                fields.add_meta_field_with(
+                    mlua::MetaMethod::from(String::from("__name")),
+                    |_| Ok(#name_text),
+                );
+            },
+        )
+    };
+    Ok(quote! {
+        impl #impl_generics mlua::UserData for #name #ty_generics #where_clause {
+            fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+                #class_name
+                #(#fields)*
+            }
+            fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+                #metamethods
+                #methods
+            }
+        }
+    })
+}
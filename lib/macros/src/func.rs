@@ -1,9 +1,49 @@
-use darling::{FromMeta, ToTokens};
+use darling::FromMeta;
 use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
 use syn::{parse_quote, punctuated::Punctuated, FnArg, PatType, Token};
 
 #[derive(FromMeta, Debug)]
-pub struct LuaFunctionMeta {}
+pub struct LuaFunctionMeta {
+    /// How errors are returned to lua
+    #[darling(default)]
+    returns: ReturnConvention,
+    /// A function that determines the error code for the "nil_err" convention
+    #[darling(default)]
+    error_code: Option<syn::Path>,
+    /// Whether to catch panics (overriding the module default)
+    #[darling(default)]
+    catch_panic: Option<bool>,
+    /// Register with `create_function_mut`, allowing `#[lua(state)]` parameters
+    ///
+    /// Calling the function again while it is running (from a callback) is an error.
+    #[darling(default, rename = "mut")]
+    mutable: darling::util::Flag,
+}
+
+/// The conventions for returning errors to lua
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ReturnConvention {
+    /// Raise errors using `error`
+    Raise,
+    /// Return `nil, message` on failure (like the `io` library)
+    NilErr,
+}
+impl Default for ReturnConvention {
+    #[inline]
+    fn default() -> Self {
+        ReturnConvention::Raise
+    }
+}
+impl FromMeta for ReturnConvention {
+    fn from_string(value: &str) -> darling::Result<Self> {
+        match value {
+            "raise" => Ok(ReturnConvention::Raise),
+            "nil_err" => Ok(ReturnConvention::NilErr),
+            _ => Err(darling::Error::unknown_value(value)),
+        }
+    }
+}
 
 macro_rules! require_matches {
     ($value:expr, $target:pat) => {
@@ -23,7 +63,7 @@ macro_rules! require_matches {
 
 /// Valid names for the lua parameter
 const VALID_LUA_MARKER_NAMES: &[&str] = &["lua", "_lua"];
-fn is_lua_marker_arg(arg: &syn::FnArg) -> bool {
+pub fn is_lua_marker_arg(arg: &syn::FnArg) -> bool {
     match *arg {
         syn::FnArg::Typed(ref tp) => {
             matches!(
@@ -35,8 +75,675 @@ fn is_lua_marker_arg(arg: &syn::FnArg) -> bool {
     }
 }
 
+/// The names that are considered to be a `Result`
+const RESULT_TYPE_NAMES: &[&str] = &["Result", "LuaResult"];
+
+/// The last path segment of a type like `mlua::Result<T>`,
+/// if it is one of the [`RESULT_TYPE_NAMES`]
+fn result_type(ty: &syn::Type) -> Option<&syn::PathSegment> {
+    match *ty {
+        syn::Type::Path(ref path) if path.qself.is_none() => path
+            .path
+            .segments
+            .last()
+            .filter(|last| RESULT_TYPE_NAMES.iter().any(|name| last.ident == name)),
+        _ => None,
+    }
+}
+
+/// Check if the return type is some sort of `Result`
+///
+/// This is a syntactic check, so it considers anything named `Result` or `LuaResult`,
+/// including aliases like `mlua::Result` and `io::Result`
+/// (but not other aliases like `SearchResult`).
+pub fn is_result_type(output: &syn::ReturnType) -> bool {
+    match *output {
+        syn::ReturnType::Type(_, ref ty) => result_type(ty).is_some(),
+        syn::ReturnType::Default => false,
+    }
+}
+
+/// The only generic argument of a type named like `Variadic<T>` or `Option<T>`
+fn generic_argument<'a>(ty: &'a syn::Type, name: &str) -> Option<&'a syn::Type> {
+    let last = match *ty {
+        syn::Type::Path(ref path) if path.qself.is_none() => path.path.segments.last()?,
+        _ => return None,
+    };
+    if last.ident != name {
+        return None;
+    }
+    match last.arguments {
+        syn::PathArguments::AngleBracketed(ref args) if args.args.len() == 1 => {
+            match args.args[0] {
+                syn::GenericArgument::Type(ref element) => Some(element),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Primitive types, along with the lua type they expect when choosing between overloads
+const PRIMITIVE_LUA_TYPES: &[(&str, &str)] = &[
+    ("bool", "Boolean"),
+    ("i8", "Number"),
+    ("i16", "Number"),
+    ("i32", "Number"),
+    ("i64", "Number"),
+    ("i128", "Number"),
+    ("isize", "Number"),
+    ("u8", "Number"),
+    ("u16", "Number"),
+    ("u32", "Number"),
+    ("u64", "Number"),
+    ("u128", "Number"),
+    ("usize", "Number"),
+    ("f32", "Number"),
+    ("f64", "Number"),
+    ("String", "String"),
+    ("StdString", "String"),
+];
+
+/// The lua type expected by a primitive parameter like `i64` or `Option<String>`,
+/// along with whether `nil` is also accepted.
+///
+/// This is a syntactic check, so other types are converted without checking.
+fn expected_lua_type(ty: &syn::Type) -> Option<(TokenStream, bool)> {
+    if let Some(inner) = generic_argument(ty, "Option") {
+        return expected_lua_type(inner).map(|(expected, _)| (expected, true));
+    }
+    let last = match *ty {
+        syn::Type::Path(ref path) if path.qself.is_none() => path.path.segments.last()?,
+        _ => return None,
+    };
+    if !last.arguments.is_empty() {
+        return None;
+    }
+    let &(_, variant) = PRIMITIVE_LUA_TYPES
+        .iter()
+        .find(|&&(name, _)| last.ident == name)?;
+    let variant = format_ident!("{}", variant);
+    Some((quote!(luao3::parse_helpers::LuaType::#variant), false))
+}
+
+/// Remove the extra whitespace from stringified tokens,
+/// so `Option < Vec < i64 > >` becomes `Option<Vec<i64>>`
+fn tidy_tokens(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => {
+                let prev = res.chars().last();
+                let next = chars.peek().copied();
+                let glued = matches!(prev, Some('<' | '&' | '[' | '(' | ':'))
+                    || matches!(next, Some('<' | '>' | ',' | ';' | ']' | ')' | ':'));
+                if !glued {
+                    res.push(' ');
+                }
+            }
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+/// Check if the function returns `impl Iterator` (possibly inside a `Result`),
+/// which is automatically wrapped in a `luao3::LuaIter`
+fn returns_iterator(output: &syn::ReturnType) -> bool {
+    fn is_impl_iterator(ty: &syn::Type) -> bool {
+        match *ty {
+            syn::Type::ImplTrait(ref imp) => imp.bounds.iter().any(|bound| {
+                matches!(bound, syn::TypeParamBound::Trait(ref tr)
+                    if matches!(tr.path.segments.last(), Some(last) if last.ident == "Iterator"))
+            }),
+            _ => false,
+        }
+    }
+    match *output {
+        syn::ReturnType::Type(_, ref ty) if is_impl_iterator(ty) => true,
+        syn::ReturnType::Type(_, ref ty) => match result_type(ty).map(|last| &last.arguments) {
+            Some(syn::PathArguments::AngleBracketed(ref args)) => {
+                matches!(args.args.first(), Some(syn::GenericArgument::Type(ref ty))
+                    if is_impl_iterator(ty))
+            }
+            _ => false,
+        },
+        syn::ReturnType::Default => false,
+    }
+}
+
+/// The options that control how a wrapper is generated,
+/// shared between functions and methods.
+#[derive(Debug, Default)]
+pub struct WrapperOptions {
+    pub returns: ReturnConvention,
+    pub error_code: Option<syn::Path>,
+}
+
+/// The receiver of a method (if any)
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ReceiverKind {
+    /// `&self`
+    Ref,
+    /// `&mut self`
+    Mut,
+}
+
+/// How a parameter is converted from its lua argument
+#[derive(Copy, Clone, Debug)]
+enum ArgKind<'a> {
+    /// A plain value, converted with `FromLua`
+    Value(&'a syn::Type),
+    /// `&T`, borrowed from a userdata
+    Borrow(&'a syn::Type),
+    /// `&mut T`, mutably borrowed from a userdata
+    BorrowMut(&'a syn::Type),
+    /// A `luao3::Callback`, which remembers its position
+    Callback(&'a syn::Type),
+    /// `#[lua(app_data)] &T`, fetched with `Lua::app_data_ref`
+    AppData(&'a syn::Type),
+    /// `#[lua(app_data)] &mut T`, fetched with `Lua::app_data_mut`
+    AppDataMut(&'a syn::Type),
+    /// `#[lua(state)] &mut T`, owned by the function itself
+    State(&'a syn::Type),
+    /// `#[lua(module_state)] &T`, shared by the whole module
+    ModuleState(&'a syn::Type),
+    /// `#[lua(module_state)] &mut T`, shared by the whole module
+    ModuleStateMut(&'a syn::Type),
+}
+/// Types that are known not to be userdata, so they can't be borrowed from the arguments
+const NON_USERDATA_TYPES: &[&str] = &[
+    "str",
+    "String",
+    "Vec",
+    "Option",
+    "HashMap",
+    "BTreeMap",
+    "Table",
+    "Function",
+    "Value",
+    "MultiValue",
+    "AnyUserData",
+    "Thread",
+    "LuaTable",
+    "LuaFunction",
+    "LuaValue",
+    "LuaString",
+];
+
+/// Check that a reference parameter refers to something that could be a userdata,
+/// since those are the only arguments that can be borrowed.
+fn check_userdata_type(ty: &syn::Type, referent: &syn::Type) -> Result<(), darling::Error> {
+    let is_userdata = match *referent {
+        syn::Type::Path(ref path) if path.qself.is_none() => match path.path.segments.last() {
+            Some(last) => {
+                !NON_USERDATA_TYPES.iter().any(|name| last.ident == name)
+                    && !PRIMITIVE_LUA_TYPES
+                        .iter()
+                        .any(|&(name, _)| last.ident == name)
+            }
+            None => false,
+        },
+        _ => false,
+    };
+    if is_userdata {
+        Ok(())
+    } else {
+        Err(darling::Error::custom(format!(
+            "Reference parameters borrow a userdata, so `{}` isn't supported \
+            (take an owned value like `String` or `mlua::Table` instead)",
+            tidy_tokens(&quote!(#ty).to_string())
+        ))
+        .with_span(ty))
+    }
+}
+
+impl<'a> ArgKind<'a> {
+    fn from_param(ty: &'a syn::Type, meta: &ParamMeta) -> Result<Self, darling::Error> {
+        let flags = [&meta.app_data, &meta.state, &meta.module_state];
+        if flags.iter().filter(|flag| flag.is_some()).count() > 1 {
+            return Err(darling::Error::custom(
+                "Conflicting `app_data`, `state` and `module_state` attributes",
+            )
+            .with_span(ty));
+        }
+        if meta.state.is_some() {
+            return match *ty {
+                syn::Type::Reference(ref reference) if reference.mutability.is_some() => {
+                    Ok(ArgKind::State(&reference.elem))
+                }
+                _ => Err(darling::Error::custom("State parameters must be `&mut T`").with_span(ty)),
+            };
+        }
+        if meta.module_state.is_some() {
+            return match *ty {
+                syn::Type::Reference(ref reference) if reference.mutability.is_some() => {
+                    Ok(ArgKind::ModuleStateMut(&reference.elem))
+                }
+                syn::Type::Reference(ref reference) => Ok(ArgKind::ModuleState(&reference.elem)),
+                _ => Err(darling::Error::custom(
+                    "Module state parameters must be references (`&T` or `&mut T`)",
+                )
+                .with_span(ty)),
+            };
+        }
+        if meta.app_data.is_some() {
+            return match *ty {
+                syn::Type::Reference(ref reference) if reference.mutability.is_some() => {
+                    Ok(ArgKind::AppDataMut(&reference.elem))
+                }
+                syn::Type::Reference(ref reference) => Ok(ArgKind::AppData(&reference.elem)),
+                _ => Err(darling::Error::custom(
+                    "App data parameters must be references (`&T` or `&mut T`)",
+                )
+                .with_span(ty)),
+            };
+        }
+        if let syn::Type::Reference(ref reference) = *ty {
+            check_userdata_type(ty, &reference.elem)?;
+        }
+        Ok(match *ty {
+            syn::Type::Reference(ref reference) if reference.mutability.is_some() => {
+                ArgKind::BorrowMut(&reference.elem)
+            }
+            syn::Type::Reference(ref reference) => ArgKind::Borrow(&reference.elem),
+            _ if crate::utils::is_callback_type(ty) => ArgKind::Callback(ty),
+            _ => ArgKind::Value(ty),
+        })
+    }
+    /// Whether this parameter comes from the lua arguments
+    fn is_lua_arg(&self) -> bool {
+        !matches!(
+            *self,
+            ArgKind::AppData(_)
+                | ArgKind::AppDataMut(_)
+                | ArgKind::State(_)
+                | ArgKind::ModuleState(_)
+                | ArgKind::ModuleStateMut(_)
+        )
+    }
+    /// Whether this parameter needs state that lives outside of the call
+    fn is_state(&self) -> bool {
+        matches!(
+            *self,
+            ArgKind::State(_) | ArgKind::ModuleState(_) | ArgKind::ModuleStateMut(_)
+        )
+    }
+}
+
+/// The `#[lua(...)]` attribute on an individual parameter
+#[derive(FromMeta, Debug, Default)]
+struct ParamMeta {
+    /// Fetch this parameter from `Lua::app_data_ref` instead of the lua arguments
+    #[darling(default)]
+    app_data: darling::util::Flag,
+    /// State owned by this function, which persists between calls
+    #[darling(default)]
+    state: darling::util::Flag,
+    /// State shared by the entire module (declared with `state => ...`)
+    #[darling(default)]
+    module_state: darling::util::Flag,
+}
+
+/// Remove the `#[lua(...)]` attributes from the parameters,
+/// which have already been parsed by [`Wrapper::from_signature`]
+pub fn strip_param_attrs(sig: &mut syn::Signature) {
+    for input in sig.inputs.iter_mut() {
+        if let FnArg::Typed(ref mut param) = *input {
+            param.attrs.retain(|attr| !attr.path.is_ident("lua"));
+        }
+    }
+}
+
+/// A rust function, which is being wrapped so it can be called with lua arguments
+pub struct Wrapper<'a> {
+    receiver: Option<ReceiverKind>,
+    takes_lua: bool,
+    args: Vec<ArgKind<'a>>,
+    arg_pats: Vec<&'a syn::Pat>,
+    is_result: bool,
+    returns_iterator: bool,
+    options: WrapperOptions,
+}
+impl<'a> Wrapper<'a> {
+    pub fn from_signature(
+        sig: &'a syn::Signature,
+        options: WrapperOptions,
+    ) -> Result<Self, darling::Error> {
+        require_matches!(sig.constness, None);
+        require_matches!(sig.asyncness, None);
+        require_matches!(sig.unsafety, None);
+        require_matches!(sig.abi, None);
+        require_matches!(sig.variadic, None);
+        if let Some(param) = sig
+            .generics
+            .params
+            .iter()
+            .find(|param| !matches!(param, syn::GenericParam::Lifetime(_)))
+        {
+            return Err(
+                darling::Error::custom("Lua can't infer generic parameters").with_span(param)
+            );
+        }
+        let mut original_arg_iter = sig.inputs.iter().peekable();
+        let receiver = match original_arg_iter.next_if(|arg| matches!(arg, FnArg::Receiver(_))) {
+            Some(FnArg::Receiver(ref receiver)) => match receiver.reference {
+                Some(_) if receiver.mutability.is_some() => Some(ReceiverKind::Mut),
+                Some(_) => Some(ReceiverKind::Ref),
+                None => {
+                    return Err(
+                        darling::Error::custom("Methods must take `&self` or `&mut self`")
+                            .with_span(receiver),
+                    );
+                }
+            },
+            _ => None,
+        };
+        let takes_lua = original_arg_iter
+            .next_if(|arg| is_lua_marker_arg(arg))
+            .is_some();
+        let mut args = Vec::new();
+        let mut arg_pats = Vec::new();
+        for remaining in original_arg_iter {
+            match remaining {
+                FnArg::Receiver(ref arg) => {
+                    return Err(darling::Error::custom("Unexpected reciever arg").with_span(arg));
+                }
+                FnArg::Typed(PatType {
+                    ty,
+                    pat,
+                    attrs,
+                    colon_token: _,
+                }) => {
+                    let meta = crate::utils::parse_lua_attrs::<ParamMeta>(attrs)?;
+                    args.push(ArgKind::from_param(ty, &meta)?);
+                    arg_pats.push(&**pat);
+                }
+            }
+        }
+        // All app data is stored in a single `RefCell`
+        let app_data_count = args
+            .iter()
+            .filter(|arg| matches!(arg, ArgKind::AppData(_) | ArgKind::AppDataMut(_)))
+            .count();
+        if app_data_count > 1 && args.iter().any(|arg| matches!(arg, ArgKind::AppDataMut(_))) {
+            return Err(darling::Error::custom(
+                "A `&mut` app data parameter can't be combined with other app data parameters",
+            )
+            .with_span(&sig.inputs));
+        }
+        let is_result = is_result_type(&sig.output);
+        if options.error_code.is_some() && options.returns != ReturnConvention::NilErr {
+            return Err(darling::Error::custom(
+                "An `error_code` requires `returns = \"nil_err\"`",
+            ));
+        }
+        if options.returns == ReturnConvention::NilErr && !is_result {
+            return Err(darling::Error::custom(
+                "Using `returns = \"nil_err\"` requires returning a `Result`",
+            )
+            .with_span(&sig.output));
+        }
+        Ok(Wrapper {
+            receiver,
+            takes_lua,
+            args,
+            arg_pats,
+            is_result,
+            returns_iterator: returns_iterator(&sig.output),
+            options,
+        })
+    }
+    #[inline]
+    pub fn receiver(&self) -> Option<ReceiverKind> {
+        self.receiver
+    }
+    /// Whether the function needs state that lives outside of each call
+    pub fn is_stateful(&self) -> bool {
+        self.args.iter().any(ArgKind::is_state)
+    }
+    /// Generate code that initializes the state needed by the function,
+    /// which is expected to be captured by the closure that invokes [`Wrapper::call_body`].
+    ///
+    /// This expects `options: &luao3::FunctionOptions` to be in scope.
+    pub fn init_state(&self, func_name: &TokenStream) -> TokenStream {
+        let init = self
+            .args
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, arg)| match *arg {
+                ArgKind::State(ty) => {
+                    let state = format_ident!("__state{}", idx);
+                    Some(quote!(let mut #state: #ty = Default::default();))
+                }
+                ArgKind::ModuleState(ty) | ArgKind::ModuleStateMut(ty) => {
+                    let state = format_ident!("__module_state{}", idx);
+                    Some(quote! {
+                        let #state = luao3::func::module_state::<#ty>(#func_name, options)?;
+                    })
+                }
+                _ => None,
+            });
+        quote!(#(#init)*)
+    }
+    /// The number of parameters that come from the lua arguments
+    pub fn param_count(&self) -> usize {
+        self.args.iter().filter(|arg| arg.is_lua_arg()).count()
+    }
+    /// A human-readable signature like `vec(x: f64, y: f64)`,
+    /// which only includes the parameters that come from lua.
+    pub fn signature(&self, name: &str) -> String {
+        let params = self
+            .args
+            .iter()
+            .zip(&self.arg_pats)
+            .filter(|(arg, _)| arg.is_lua_arg())
+            .map(|(arg, pat)| {
+                let ty = match *arg {
+                    ArgKind::Value(ty) | ArgKind::Callback(ty) => quote!(#ty),
+                    ArgKind::Borrow(ty) => quote!(&#ty),
+                    ArgKind::BorrowMut(ty) => quote!(&mut #ty),
+                    _ => unreachable!("not a lua arg: {:?}", arg),
+                };
+                format!("{}: {}", quote!(#pat), tidy_tokens(&ty.to_string()))
+            })
+            .collect::<Vec<_>>();
+        format!("{}({})", name, params.join(", "))
+    }
+    /// Convert a `Result` into lua values using the "nil, err" convention
+    fn nil_err_match(&self, result: TokenStream) -> TokenStream {
+        let error_values = match self.options.error_code {
+            Some(ref code) => quote!((mlua::Value::Nil, err.to_string(), #code(&err))),
+            None => quote!((mlua::Value::Nil, err.to_string())),
+        };
+        quote!(match #result {
+            Ok(value) => mlua::ToLuaMulti::to_lua_multi(value, lua)?,
+            Err(err) => mlua::ToLuaMulti::to_lua_multi(#error_values, lua)?,
+        })
+    }
+    /// Generate code that converts the lua arguments, calls the function,
+    /// and then converts the result back into a `mlua::MultiValue`.
+    ///
+    /// This expects `lua` and `__args: luao3::parse_helpers::FunctionArgs` to be in scope
+    /// (along with `this` for methods).
+    /// The `func_name` is an expression used in error messages,
+    /// and `target` is the path to the function being called.
+    pub fn call_body(&self, func_name: &TokenStream, target: &TokenStream) -> TokenStream {
+        let mut call_args: Punctuated<syn::Expr, Token![,]> = Punctuated::new();
+        if self.receiver.is_some() {
+            call_args.push(parse_quote!(this));
+        }
+        if self.takes_lua {
+            call_args.push(parse_quote!(lua));
+        }
+        // The last argument gets everything that is left (so Variadic works),
+        // unless it is a primitive that can be checked like the others
+        let rest_arg = self
+            .args
+            .iter()
+            .rposition(ArgKind::is_lua_arg)
+            .filter(|&idx| match self.args[idx] {
+                ArgKind::Value(ty) => expected_lua_type(ty).is_none(),
+                _ => false,
+            });
+        let parse_args = self
+            .args
+            .iter()
+            .enumerate()
+            .map(|(idx, arg)| {
+                let arg_name = format_ident!("__arg{}", idx);
+                match *arg {
+                    ArgKind::Value(ty) if Some(idx) == rest_arg => {
+                        call_args.push(parse_quote!(#arg_name));
+                        match generic_argument(ty, "Variadic") {
+                            // Convert one at a time, so errors have the right position
+                            Some(element) => {
+                                let expected = match expected_lua_type(element) {
+                                    Some((expected, false)) => quote!(Some(#expected)),
+                                    _ => quote!(None),
+                                };
+                                quote! {
+                                    let #arg_name: #ty = __args.remaining_variadic::<#element>(#expected)?;
+                                }
+                            }
+                            None => {
+                                // Overloads only take as many values as the type expects
+                                let limit = match *ty {
+                                    syn::Type::Path(ref path)
+                                        if path.path.segments.last().map(|last| &last.ident)
+                                            == Some(&format_ident!("MultiValue")) =>
+                                    {
+                                        quote!(None)
+                                    }
+                                    syn::Type::Tuple(ref tuple) => {
+                                        let len = tuple.elems.len();
+                                        quote!(Some(#len))
+                                    }
+                                    _ => quote!(Some(1)),
+                                };
+                                quote!(let #arg_name: #ty = __args.remaining_args::<#ty>(#limit)?;)
+                            }
+                        }
+                    }
+                    ArgKind::Value(ty) => {
+                        call_args.push(parse_quote!(#arg_name));
+                        match expected_lua_type(ty) {
+                            Some((expected, optional)) => quote! {
+                                let #arg_name: #ty = __args.next_arg_of::<#ty>(#expected, #optional)?;
+                            },
+                            None => quote!(let #arg_name: #ty = __args.next_arg::<#ty>()?;),
+                        }
+                    }
+                    ArgKind::AppData(ty) => {
+                        call_args.push(parse_quote!(&*#arg_name));
+                        quote! {
+                            let #arg_name = luao3::parse_helpers::app_data_ref::<#ty>(lua, #func_name)?;
+                        }
+                    }
+                    ArgKind::AppDataMut(ty) => {
+                        call_args.push(parse_quote!(&mut *#arg_name));
+                        quote! {
+                            let mut #arg_name = luao3::parse_helpers::app_data_mut::<#ty>(lua, #func_name)?;
+                        }
+                    }
+                    ArgKind::State(_) => {
+                        let state = format_ident!("__state{}", idx);
+                        call_args.push(parse_quote!(&mut #state));
+                        quote!()
+                    }
+                    ArgKind::ModuleState(ty) => {
+                        let state = format_ident!("__module_state{}", idx);
+                        call_args.push(parse_quote!(&*#arg_name));
+                        quote! {
+                            let #arg_name = luao3::parse_helpers::borrow_module_state::<#ty>(&#state, #func_name)?;
+                        }
+                    }
+                    ArgKind::ModuleStateMut(ty) => {
+                        let state = format_ident!("__module_state{}", idx);
+                        call_args.push(parse_quote!(&mut *#arg_name));
+                        quote! {
+                            let mut #arg_name = luao3::parse_helpers::borrow_module_state_mut::<#ty>(&#state, #func_name)?;
+                        }
+                    }
+                    ArgKind::Callback(ty) => {
+                        call_args.push(parse_quote!(#arg_name));
+                        quote!(let #arg_name: #ty = __args.next_callback()?;)
+                    }
+                    ArgKind::Borrow(ty) => {
+                        let userdata = format_ident!("__arg{}_userdata", idx);
+                        call_args.push(parse_quote!(&*#arg_name));
+                        quote! {
+                            let #userdata = __args.next_userdata::<#ty>()?;
+                            let #arg_name = #userdata.borrow::<#ty>()?;
+                        }
+                    }
+                    ArgKind::BorrowMut(ty) => {
+                        let userdata = format_ident!("__arg{}_userdata", idx);
+                        call_args.push(parse_quote!(&mut *#arg_name));
+                        quote! {
+                            let #userdata = __args.next_userdata::<#ty>()?;
+                            let mut #arg_name = #userdata.borrow_mut::<#ty>()?;
+                        }
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        // `remaining_args` consumes the arguments, but every other conversion needs `&mut`
+        let needs_mut = self.args.iter().enumerate().any(|(idx, arg)| match *arg {
+            ArgKind::Value(_) => Some(idx) != rest_arg,
+            ArgKind::Borrow(_) | ArgKind::BorrowMut(_) | ArgKind::Callback(_) => true,
+            _ => false,
+        });
+        // Overloads need to reject extra arguments, unless the last one takes the rest
+        let finish = match rest_arg {
+            Some(_) => quote!(),
+            None => quote!(__args.finish()?;),
+        };
+        let parse_args = if needs_mut {
+            quote! {
+                let mut __args = __args;
+                #(#parse_args)*
+                #finish
+            }
+        } else {
+            quote! {
+                #(#parse_args)*
+                #finish
+            }
+        };
+        let call_func = quote!(#target(#call_args));
+        let call_func = match self.options.returns {
+            ReturnConvention::Raise if self.is_result && self.returns_iterator => {
+                quote!(luao3::LuaIter::new(#call_func.map_err(luao3::func::into_lua_error)?))
+            }
+            ReturnConvention::Raise if self.is_result => {
+                quote!(#call_func.map_err(luao3::func::into_lua_error)?)
+            }
+            ReturnConvention::Raise if self.returns_iterator => {
+                quote!(luao3::LuaIter::new(#call_func))
+            }
+            ReturnConvention::Raise => call_func,
+            ReturnConvention::NilErr if self.returns_iterator => {
+                self.nil_err_match(quote!(#call_func.map(luao3::LuaIter::new)))
+            }
+            ReturnConvention::NilErr => self.nil_err_match(call_func),
+        };
+        quote! {
+            #parse_args
+            let result = #call_func;
+            mlua::ToLuaMulti::to_lua_multi(result, lua)
+        }
+    }
+}
+
 pub fn expand(meta: LuaFunctionMeta, item: syn::Item) -> Result<TokenStream, darling::Error> {
-    let LuaFunctionMeta {} = meta;
+    let LuaFunctionMeta {
+        returns,
+        error_code,
+        catch_panic,
+        mutable,
+    } = meta;
     let mut func = match item {
         syn::Item::Fn(func) => func,
         _ => {
@@ -45,69 +752,104 @@ pub fn expand(meta: LuaFunctionMeta, item: syn::Item) -> Result<TokenStream, dar
             ))
         }
     };
-    // Rewrite the signature (that's 99% of what we do)
+    // The original function is left alone (besides parameter attributes),
+    // we just generate a wrapper around it
     let sig = &func.sig;
-    require_matches!(sig.constness, None);
-    require_matches!(sig.asyncness, None);
-    require_matches!(sig.unsafety, None);
-    require_matches!(sig.abi, None);
-    require_matches!(sig.variadic, None);
-    let syn::Signature {
-        ref generics,
-        paren_token: _,
-        fn_token: _,
-        ref inputs,
-        ..
-    } = *sig;
-    let mut rewritten_generics = generics.clone();
-    if !rewritten_generics
-        .lifetimes()
-        .any(|lt| lt.lifetime.ident == "lua")
-    {
-        rewritten_generics.params.push(parse_quote!('lua));
-    }
-    let mut original_arg_iter = inputs.iter().peekable();
-    let mut rewritten_args: Punctuated<syn::FnArg, Token![,]> = Punctuated::new();
-    if original_arg_iter
-        .peek()
-        .copied()
-        .map_or(false, is_lua_marker_arg)
-    {
-        rewritten_args.push(original_arg_iter.next().unwrap().clone());
-    } else {
-        rewritten_args.push(parse_quote!(__lua: &'lua mlua::Lua))
-    };
-    let mut remaining_arg_types: Punctuated<syn::Type, Token![,]> = Punctuated::new();
-    let mut remaining_arg_patterns: Punctuated<syn::Pat, Token![,]> = Punctuated::new();
-    for remaining in original_arg_iter {
-        match remaining {
-            FnArg::Receiver(ref arg) => {
-                return Err(darling::Error::custom("Unexpected reciever arg").with_span(arg));
-            }
-            FnArg::Typed(PatType {
-                ty,
-                pat,
-                attrs: _, //TODO
-                colon_token: _,
-            }) => {
-                remaining_arg_types.push((**ty).clone());
-                remaining_arg_patterns.push((**pat).clone());
-            }
-        }
+    let wrapper = Wrapper::from_signature(
+        sig,
+        WrapperOptions {
+            returns,
+            error_code,
+        },
+    )?;
+    if wrapper.receiver().is_some() {
+        return Err(darling::Error::custom(
+            "Unexpected reciever arg (use #[lua_methods] on the impl block)",
+        )
+        .with_span(&sig.inputs));
     }
-    if !remaining_arg_patterns.empty_or_trailing() {
-        remaining_arg_patterns.push_punct(Default::default());
+    let name = sig.ident.clone();
+    let vis = func.vis.clone();
+    let body = wrapper.call_body(&quote!(Self::NAME), &quote!(#name));
+    let name_text = name.to_string();
+    let signature = wrapper.signature(&name_text);
+    let param_count = wrapper.param_count();
+    let has_local_state = wrapper
+        .args
+        .iter()
+        .any(|arg| matches!(arg, ArgKind::State(_)));
+    if has_local_state && mutable.is_none() {
+        return Err(darling::Error::custom(
+            "Using `#[lua(state)]` parameters requires `#[lua_function(mut)]`",
+        )
+        .with_span(&sig.inputs));
     }
-
-    if !remaining_arg_types.empty_or_trailing() {
-        remaining_arg_types.push_punct(Default::default());
+    if mutable.is_some() && !has_local_state {
+        return Err(darling::Error::custom(
+            "`#[lua_function(mut)]` is only needed for `#[lua(state)]` parameters",
+        )
+        .with_span(&sig.ident));
     }
-    rewritten_args.push(parse_quote!((#remaining_arg_patterns): (#remaining_arg_types)));
-    let rewritten_sig = syn::Signature {
-        inputs: rewritten_args,
-        generics: rewritten_generics,
-        ..sig.clone()
+    let stateful = if wrapper.is_stateful() {
+        let init_state = wrapper.init_state(&quote!(Self::NAME));
+        let create_function = if mutable.is_some() {
+            quote!(create_function_mut)
+        } else {
+            quote!(create_function)
+        };
+        Some(quote! {
+            const STATEFUL: bool = true;
+            fn create_with<'lua>(
+                lua: &'lua mlua::Lua,
+                options: &luao3::FunctionOptions,
+            ) -> mlua::Result<mlua::Function<'lua>> {
+                #init_state
+                let catch_panic = Self::CATCH_PANIC.unwrap_or(options.catch_panics);
+                let capture_backtrace = options.capture_backtrace;
+                lua.#create_function(move |lua, __args: mlua::MultiValue| {
+                    let __args = luao3::parse_helpers::FunctionArgs::new(lua, Self::NAME, __args);
+                    #[allow(unused_mut)]
+                    let mut call = || { #body };
+                    if catch_panic {
+                        luao3::panic::catch_panic(Self::NAME, capture_backtrace, call)
+                    } else {
+                        call()
+                    }
+                })
+            }
+        })
+    } else {
+        None
     };
-    func.sig = rewritten_sig;
-    Ok(func.into_token_stream())
+    // Stateful functions can only be called through the closure created by `create_with`
+    let body = if wrapper.is_stateful() {
+        quote! {
+            let _ = (lua, __args);
+            Err(luao3::func::stateful_call_error(Self::NAME))
+        }
+    } else {
+        body
+    };
+    strip_param_attrs(&mut func.sig);
+    let catch_panic = catch_panic
+        .map(|catch_panic| quote!(const CATCH_PANIC: Option<bool> = Some(#catch_panic);));
+    Ok(quote! {
+        #func
+        #[doc(hidden)]
+        #[allow(non_camel_case_types, dead_code)]
+        #vis struct #name {}
+        impl luao3::LuaFunction for #name {
+            const NAME: &'static str = #name_text;
+            const SIGNATURE: &'static str = #signature;
+            const PARAM_COUNT: usize = #param_count;
+            #catch_panic
+            #stateful
+            fn call_args<'lua>(
+                lua: &'lua mlua::Lua,
+                __args: luao3::parse_helpers::FunctionArgs<'lua>,
+            ) -> mlua::Result<mlua::MultiValue<'lua>> {
+                #body
+            }
+        }
+    })
 }
@@ -0,0 +1,234 @@
+use darling::FromMeta;
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::FnArg;
+
+use crate::func::is_lua_marker_arg;
+use crate::utils::take_lua_attrs;
+
+#[derive(FromMeta, Debug, Default)]
+pub struct LuaImportMeta {
+    /// Import a global function
+    #[darling(default)]
+    global: Option<String>,
+    /// Import a function at a dotted path, like "game.hooks.on_tick"
+    #[darling(default)]
+    path: Option<String>,
+    /// Import a function stored in a field of the table passed as the first argument
+    #[darling(default)]
+    field: Option<String>,
+    /// The table containing the functions of a trait (defaults to the globals)
+    #[darling(default)]
+    table: Option<String>,
+}
+
+/// The `#[lua(...)]` attribute on a trait method
+#[derive(FromMeta, Debug, Default)]
+pub struct ImportMethodMeta {
+    /// Override the name of the lua function
+    #[darling(default)]
+    pub name: Option<String>,
+}
+
+pub fn expand(meta: LuaImportMeta, item: syn::Item) -> Result<TokenStream, darling::Error> {
+    match item {
+        syn::Item::Trait(item) => expand_trait(meta, item),
+        syn::Item::Verbatim(tokens) => match syn::parse2::<syn::ForeignItemFn>(tokens) {
+            Ok(func) => expand_fn(meta, func),
+            Err(e) => Err(e.into()),
+        },
+        syn::Item::Fn(func) => Err(darling::Error::custom(
+            "Imported functions shouldn't have a body (`fn foo(lua: &Lua) -> LuaResult<()>;`)",
+        )
+        .with_span(&func.block)),
+        _ => Err(darling::Error::custom(
+            "Expected a function declaration or a trait",
+        )),
+    }
+}
+
+/// Convert the remaining parameters into a tuple of arguments
+pub fn args_tuple<'a>(
+    params: impl Iterator<Item = &'a FnArg>,
+) -> Result<TokenStream, darling::Error> {
+    let mut args = Vec::new();
+    for param in params {
+        match *param {
+            FnArg::Typed(ref param) => match *param.pat {
+                syn::Pat::Ident(ref pat) => args.push(pat.ident.clone()),
+                ref other => {
+                    return Err(
+                        darling::Error::custom("Expected a simple parameter name").with_span(other)
+                    )
+                }
+            },
+            FnArg::Receiver(ref receiver) => {
+                return Err(darling::Error::custom("Unexpected receiver").with_span(receiver))
+            }
+        }
+    }
+    Ok(quote!((#(#args,)*)))
+}
+
+fn param_name(param: Option<&FnArg>) -> Option<&syn::Ident> {
+    match param {
+        Some(FnArg::Typed(ref param)) => match *param.pat {
+            syn::Pat::Ident(ref pat) => Some(&pat.ident),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Where an imported function is found
+enum Source {
+    Global(String),
+    Path(String),
+    Field(String),
+}
+
+pub fn check_signature(sig: &syn::Signature) -> Result<(), darling::Error> {
+    if !crate::func::is_result_type(&sig.output) {
+        return Err(darling::Error::custom(
+            "Imported lua functions must return a `Result`, since calling lua can fail",
+        )
+        .with_span(&sig.output));
+    }
+    if let Some(ref asyncness) = sig.asyncness {
+        return Err(
+            darling::Error::custom("Imported functions can't be async").with_span(asyncness)
+        );
+    }
+    Ok(())
+}
+
+fn expand_fn(meta: LuaImportMeta, func: syn::ForeignItemFn) -> Result<TokenStream, darling::Error> {
+    let sig = &func.sig;
+    check_signature(sig)?;
+    if meta.table.is_some() {
+        return Err(darling::Error::custom(
+            "The `table` option is only supported on traits",
+        ));
+    }
+    let mut params = sig.inputs.iter().peekable();
+    let lua = params.next_if(|param| is_lua_marker_arg(param));
+    let lua = param_name(lua);
+    let source = match (meta.global, meta.path, meta.field) {
+        (Some(name), None, None) => Source::Global(name),
+        (None, None, None) => Source::Global(sig.ident.to_string()),
+        (None, Some(path), None) => Source::Path(path),
+        (None, None, Some(field)) => Source::Field(field),
+        _ => {
+            return Err(darling::Error::custom(
+                "Expected only one of `global`, `path` or `field`",
+            ))
+        }
+    };
+    let body = match (source, lua) {
+        (Source::Global(name) | Source::Path(name), None) => {
+            return Err(darling::Error::custom(format!(
+                "Importing '{name}' requires a `lua: &Lua` parameter"
+            ))
+            .with_span(&sig.inputs))
+        }
+        (Source::Global(name), Some(lua)) => {
+            let args = args_tuple(params)?;
+            quote!(luao3::import::call_global(#lua, #name, #args))
+        }
+        (Source::Path(path), Some(lua)) => {
+            let args = args_tuple(params)?;
+            quote!(luao3::import::call_path(#lua, #path, #args))
+        }
+        (Source::Field(field), _) => {
+            let table = match param_name(params.next()) {
+                Some(table) => table,
+                None => {
+                    return Err(darling::Error::custom(
+                        "Importing a field requires the table as a parameter",
+                    )
+                    .with_span(&sig.inputs))
+                }
+            };
+            let args = args_tuple(params)?;
+            quote!(luao3::import::call_field(&#table, #field, #args))
+        }
+    };
+    let attrs = &func.attrs;
+    let vis = &func.vis;
+    Ok(quote! {
+        #(#attrs)*
+        #vis #sig {
+            #body
+        }
+    })
+}
+
+fn expand_trait(
+    meta: LuaImportMeta,
+    mut item: syn::ItemTrait,
+) -> Result<TokenStream, darling::Error> {
+    if meta.global.is_some() || meta.path.is_some() || meta.field.is_some() {
+        return Err(darling::Error::custom(
+            "Traits only support the `table` option (use `#[lua(name = \"...\")]` on methods)",
+        ));
+    }
+    let mut errors = Vec::new();
+    let mut methods = Vec::new();
+    for trait_item in item.items.iter_mut() {
+        let method = match *trait_item {
+            syn::TraitItem::Method(ref mut method) => method,
+            _ => continue,
+        };
+        let method_meta = match take_lua_attrs::<ImportMethodMeta>(&mut method.attrs) {
+            Ok(meta) => meta,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        // Methods with a default implementation are left alone
+        if method.default.is_some() {
+            continue;
+        }
+        let sig = &method.sig;
+        let body = error_loop!(errors, Result<TokenStream, darling::Error>; {
+            check_signature(sig)?;
+            let mut params = sig.inputs.iter();
+            match params.next() {
+                Some(FnArg::Receiver(ref receiver)) if receiver.reference.is_some()
+                    && receiver.mutability.is_none() => {}
+                _ => {
+                    return Err(darling::Error::custom(
+                        "Imported trait methods must take `&self` (which is the `Lua`)",
+                    )
+                    .with_span(&sig.inputs));
+                }
+            }
+            let name = method_meta.name.clone().unwrap_or_else(|| sig.ident.to_string());
+            let args = args_tuple(params)?;
+            Ok(match meta.table {
+                Some(ref table) => {
+                    let path = format!("{table}.{name}");
+                    quote!(luao3::import::call_path(self, #path, #args))
+                }
+                None => quote!(luao3::import::call_global(self, #name, #args)),
+            })
+        });
+        methods.push(quote! {
+            #sig {
+                #body
+            }
+        });
+    }
+    if !errors.is_empty() {
+        return Err(darling::Error::multiple(errors));
+    }
+    let name = &item.ident;
+    let (impl_generics, ty_generics, where_clause) = item.generics.split_for_impl();
+    Ok(quote! {
+        #item
+        impl #impl_generics #name #ty_generics for mlua::Lua #where_clause {
+            #(#methods)*
+        }
+    })
+}
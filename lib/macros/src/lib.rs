@@ -3,6 +3,9 @@ use std::fmt::Display;
 
 use darling::FromMeta;
 use func::LuaFunctionMeta;
+use import::LuaImportMeta;
+use lua_module::LuaModuleMeta;
+use methods::LuaMethodsMeta;
 use proc_macro::TokenStream as RawTokenStream;
 use syn::parse_macro_input;
 
@@ -10,6 +13,11 @@ use syn::parse_macro_input;
 mod utils;
 mod derive;
 mod func;
+mod import;
+mod lua_module;
+mod lua_trait;
+mod metamethods;
+mod methods;
 mod simple_module;
 
 #[proc_macro_derive(FromLua, attributes(lua))]
@@ -34,6 +42,17 @@ pub fn derive_to_lua(input: RawTokenStream) -> RawTokenStream {
     maybe_debug("ToLua", &name, tk, MacroCtx::File)
 }
 
+#[proc_macro_derive(LuaUserData, attributes(lua))]
+pub fn derive_lua_userdata(input: RawTokenStream) -> RawTokenStream {
+    let derive = parse_macro_input!(input as syn::DeriveInput);
+    let name = derive.ident.clone();
+    let tk = match derive::userdata::expand(derive) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.write_errors().into(),
+    };
+    maybe_debug("LuaUserData", &name, tk, MacroCtx::File)
+}
+
 #[proc_macro_attribute]
 pub fn lua_function(args: RawTokenStream, item: RawTokenStream) -> RawTokenStream {
     let args = parse_macro_input!(args as syn::AttributeArgs);
@@ -44,6 +63,51 @@ pub fn lua_function(args: RawTokenStream, item: RawTokenStream) -> RawTokenStrea
     }
 }
 
+#[proc_macro_attribute]
+pub fn lua_methods(args: RawTokenStream, item: RawTokenStream) -> RawTokenStream {
+    let args = parse_macro_input!(args as syn::AttributeArgs);
+    let item = parse_macro_input!(item as syn::Item);
+    match LuaMethodsMeta::from_list(&args).and_then(|meta| methods::expand(meta, item)) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.write_errors().into(),
+    }
+}
+
+#[proc_macro_attribute]
+pub fn lua_import(args: RawTokenStream, item: RawTokenStream) -> RawTokenStream {
+    let args = parse_macro_input!(args as syn::AttributeArgs);
+    let item = parse_macro_input!(item as syn::Item);
+    match LuaImportMeta::from_list(&args).and_then(|meta| import::expand(meta, item)) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.write_errors().into(),
+    }
+}
+
+#[proc_macro_attribute]
+pub fn lua_trait(args: RawTokenStream, item: RawTokenStream) -> RawTokenStream {
+    let args = parse_macro_input!(args as syn::AttributeArgs);
+    let item = parse_macro_input!(item as syn::Item);
+    if let Some(arg) = args.first() {
+        return syn::Error::new_spanned(arg, "Unexpected arguments to #[lua_trait]")
+            .to_compile_error()
+            .into();
+    }
+    match lua_trait::expand(item) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.write_errors().into(),
+    }
+}
+
+#[proc_macro_attribute]
+pub fn lua_module(args: RawTokenStream, item: RawTokenStream) -> RawTokenStream {
+    let args = parse_macro_input!(args as syn::AttributeArgs);
+    let item = parse_macro_input!(item as syn::Item);
+    match LuaModuleMeta::from_list(&args).and_then(|meta| lua_module::expand(meta, item)) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.write_errors().into(),
+    }
+}
+
 #[proc_macro]
 pub fn declare_simple_module(input: RawTokenStream) -> RawTokenStream {
     let args = parse_macro_input!(input as simple_module::ModuleArgs);
@@ -0,0 +1,137 @@
+use darling::FromMeta;
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+
+use crate::simple_module::{self, LuaName, ModuleArgs, ModuleItem, ModuleItemDecl, ModuleMembers};
+use crate::utils::{self, take_lua_attrs};
+
+#[derive(FromMeta, Debug, Default)]
+pub struct LuaModuleMeta {
+    /// Convert panics in functions into lua errors (default true)
+    #[darling(default)]
+    catch_panics: Option<bool>,
+    /// Capture backtraces for panics (default false, requires `luao3::panic::install_hook`)
+    #[darling(default)]
+    panic_backtraces: Option<bool>,
+    /// State shared by all the functions in the module
+    #[darling(default)]
+    state: Option<syn::Expr>,
+    /// Also emit a `luaopen_<name>` entry point
+    #[darling(default)]
+    entry_point: darling::util::Flag,
+    /// Only create each member when it is first accessed
+    #[darling(default)]
+    lazy: darling::util::Flag,
+}
+
+/// The `#[lua(...)]` attribute on an individual item in the module
+#[derive(FromMeta, Debug, Default)]
+struct LuaItemMeta {
+    /// Override the name of the member in lua
+    #[darling(default)]
+    name: Option<String>,
+    /// Don't expose this item to lua
+    #[darling(default)]
+    skip: darling::util::Flag,
+}
+
+/// Check if the item has the specified attribute (like `#[lua_function]`),
+/// using only the last segment of the path.
+fn has_attr(attrs: &[syn::Attribute], name: &str) -> bool {
+    attrs
+        .iter()
+        .any(|attr| matches!(attr.path.segments.last(), Some(last) if last.ident == name))
+}
+
+pub fn expand(meta: LuaModuleMeta, item: syn::Item) -> Result<TokenStream, darling::Error> {
+    let mut module = match item {
+        syn::Item::Mod(module) => module,
+        _ => {
+            return Err(darling::Error::custom(
+                "Expected an inline module (`mod foo { ... }`)",
+            ))
+        }
+    };
+    let content = match module.content {
+        Some((_, ref mut content)) => content,
+        None => {
+            return Err(darling::Error::custom(
+                "Expected an inline module (`mod foo { ... }`), not a separate file",
+            )
+            .with_span(&module))
+        }
+    };
+    let mut errors = Vec::new();
+    let mut decls = Vec::new();
+    for item in content.iter_mut() {
+        let (attrs, item_kind) = match *item {
+            syn::Item::Fn(ref mut func) if has_attr(&func.attrs, "lua_function") => {
+                let path = syn::Path::from(func.sig.ident.clone());
+                (&mut func.attrs, ModuleItem::Function { path })
+            }
+            // Private constants are usually helpers, so they need an explicit `#[lua]`
+            syn::Item::Const(ref mut constant)
+                if matches!(constant.vis, syn::Visibility::Public(_))
+                    || has_attr(&constant.attrs, "lua") =>
+            {
+                let name = constant.ident.clone();
+                let value = syn::parse_quote_spanned!(name.span() => #name);
+                (&mut constant.attrs, ModuleItem::Value { name, value })
+            }
+            syn::Item::Impl(ref mut item) if has_attr(&item.attrs, "lua_methods") => {
+                let path = match *item.self_ty {
+                    syn::Type::Path(ref ty) if ty.qself.is_none() => ty.path.clone(),
+                    ref other => {
+                        errors
+                            .push(darling::Error::custom("Expected a named type").with_span(other));
+                        continue;
+                    }
+                };
+                (&mut item.attrs, ModuleItem::Class { path })
+            }
+            _ => continue,
+        };
+        let item_meta = match take_lua_attrs::<LuaItemMeta>(attrs) {
+            Ok(meta) => meta,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        if item_meta.skip.is_some() {
+            continue;
+        }
+        decls.push(ModuleItemDecl {
+            custom_name: item_meta
+                .name
+                .map(|name| LuaName::Text(syn::LitStr::new(&name, Span::call_site()))),
+            item: item_kind,
+            doc: utils::doc_string(attrs),
+        });
+    }
+    if !errors.is_empty() {
+        return Err(darling::Error::multiple(errors));
+    }
+    let members = ModuleMembers::from_decls(decls)?;
+    let span = module.ident.span();
+    let declared = simple_module::expand_module(ModuleArgs {
+        name: module.ident.clone(),
+        members,
+        vis: Some(syn::parse_quote!(pub)),
+        catch_panics: meta.catch_panics.map(|val| syn::LitBool::new(val, span)),
+        panic_backtraces: meta
+            .panic_backtraces
+            .map(|val| syn::LitBool::new(val, span)),
+        state: meta.state,
+        entry_point: meta
+            .entry_point
+            .is_some()
+            .then(|| syn::LitBool::new(true, span)),
+        doc: utils::doc_string(&module.attrs).map(|doc| syn::LitStr::new(&doc, span)),
+        lazy: meta.lazy.is_some().then(|| syn::LitBool::new(true, span)),
+    })?;
+    // NOTE: The marker type and function are both named after the module,
+    // so the module can be used as `foobar::foobar(lua)`
+    content.push(syn::Item::Verbatim(declared));
+    Ok(quote!(#module))
+}
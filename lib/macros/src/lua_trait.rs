@@ -0,0 +1,157 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote, ToTokens};
+use syn::{parse_quote, FnArg};
+
+use crate::import::{args_tuple, check_signature, ImportMethodMeta};
+use crate::utils::take_lua_attrs;
+
+pub fn expand(item: syn::Item) -> Result<TokenStream, darling::Error> {
+    let mut item = match item {
+        syn::Item::Trait(item) => item,
+        _ => return Err(darling::Error::custom("Expected a trait")),
+    };
+    let mut errors = Vec::new();
+    let mut methods = Vec::new();
+    let mut default_helpers = Vec::new();
+    let defaults_name = format_ident!("__{}Defaults", item.ident);
+    let defaults_trait = {
+        let (_, ty_generics, _) = item.generics.split_for_impl();
+        quote!(#defaults_name #ty_generics)
+    };
+    for trait_item in item.items.iter_mut() {
+        let method = match *trait_item {
+            syn::TraitItem::Method(ref mut method) => method,
+            _ => continue,
+        };
+        let method_meta = match take_lua_attrs::<ImportMethodMeta>(&mut method.attrs) {
+            Ok(meta) => meta,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        let sig = &method.sig;
+        match sig.inputs.first() {
+            Some(FnArg::Receiver(ref receiver)) if receiver.reference.is_some() => {}
+            // Static functions with defaults are left alone
+            _ if method.default.is_some() => continue,
+            _ => {
+                errors.push(
+                    darling::Error::custom("Methods must take `&self` or `&mut self`")
+                        .with_span(&sig.inputs),
+                );
+                continue;
+            }
+        }
+        if let Err(e) = check_signature(sig) {
+            errors.push(e);
+            continue;
+        }
+        if let Some(param) = sig
+            .generics
+            .params
+            .iter()
+            .find(|param| !matches!(param, syn::GenericParam::Lifetime(_)))
+        {
+            errors.push(
+                darling::Error::custom("Generic methods can't be implemented in lua")
+                    .with_span(param),
+            );
+            continue;
+        }
+        let name = method_meta.name.unwrap_or_else(|| sig.ident.to_string());
+        // Bind the parameters to fresh names, since they may be arbitrary patterns
+        let mut impl_sig = sig.clone();
+        for (idx, param) in impl_sig.inputs.iter_mut().skip(1).enumerate() {
+            if let FnArg::Typed(ref mut param) = *param {
+                let fresh = format_ident!("__arg{}", idx);
+                param.attrs.clear();
+                *param.pat = parse_quote!(#fresh);
+            }
+        }
+        let args = match args_tuple(impl_sig.inputs.iter().skip(1)) {
+            Ok(args) => args,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        let fallback = match method.default {
+            Some(_) => {
+                // Copy the default into the defaults trait, so the lua impl can fall back to it
+                let (helper, call) = default_helper(&defaults_trait, method, &impl_sig);
+                default_helpers.push(helper);
+                call
+            }
+            None => quote!(Err(luao3::import::missing_method(#name).into())),
+        };
+        methods.push(quote! {
+            #impl_sig {
+                match luao3::import::find_method(self.object(), #name)? {
+                    Some(func) => luao3::import::call_method(self.object(), &func, #name, #args),
+                    None => #fallback,
+                }
+            }
+        });
+    }
+    if !errors.is_empty() {
+        return Err(darling::Error::multiple(errors));
+    }
+    let name = &item.ident;
+    let (_, ty_generics, where_clause) = item.generics.split_for_impl();
+    let mut impl_generics = item.generics.clone();
+    impl_generics.params.insert(0, parse_quote!('lua));
+    let (impl_generics, _, _) = impl_generics.split_for_impl();
+    let defaults = if default_helpers.is_empty() {
+        None
+    } else {
+        let (trait_impl_generics, trait_ty_generics, _) = item.generics.split_for_impl();
+        let mut blanket_generics = item.generics.clone();
+        blanket_generics
+            .params
+            .push(parse_quote!(__Self: #name #trait_ty_generics + ?Sized));
+        let (blanket_generics, _, _) = blanket_generics.split_for_impl();
+        Some(quote! {
+            #[doc(hidden)]
+            #[allow(non_camel_case_types)]
+            trait #defaults_name #trait_impl_generics: #name #trait_ty_generics #where_clause {
+                #(#default_helpers)*
+            }
+            impl #blanket_generics #defaults_trait for __Self #where_clause {}
+        })
+    };
+    Ok(quote! {
+        #item
+        #defaults
+        impl #impl_generics #name #ty_generics for luao3::LuaImpl<mlua::Table<'lua>> #where_clause {
+            #(#methods)*
+        }
+    })
+}
+
+/// Generate a copy of a default trait method for the hidden defaults trait,
+/// along with an expression that calls it from the lua impl.
+///
+/// The defaults trait has the original trait as a supertrait,
+/// so the body keeps its meaning without rewriting `self` or `Self`.
+fn default_helper(
+    defaults_trait: &TokenStream,
+    method: &syn::TraitItemMethod,
+    impl_sig: &syn::Signature,
+) -> (TokenStream, TokenStream) {
+    let mut helper = method.clone();
+    let helper_name = format_ident!("__default_{}", method.sig.ident);
+    helper.attrs.clear();
+    helper.sig.ident = helper_name.clone();
+    for param in helper.sig.inputs.iter_mut() {
+        if let FnArg::Typed(ref mut param) = *param {
+            param.attrs.retain(|attr| !attr.path.is_ident("lua"));
+        }
+    }
+    let args = impl_sig.inputs.iter().skip(1).map(|param| match *param {
+        FnArg::Typed(ref param) => &param.pat,
+        FnArg::Receiver(_) => unreachable!(),
+    });
+    let call = quote!(<Self as #defaults_trait>::#helper_name(self, #(#args),*));
+    (helper.into_token_stream(), call)
+}
@@ -0,0 +1,108 @@
+use proc_macro2::TokenStream;
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+
+/// The metamethods that can be declared with `#[lua(meta = "...")]`
+const KNOWN_METAMETHODS: &[&str] = &[
+    "add", "sub", "mul", "div", "mod", "pow", "unm", "idiv", "band", "bor", "bxor", "bnot", "shl",
+    "shr", "concat", "len", "eq", "lt", "le", "index", "newindex", "call", "tostring", "pairs",
+    "close",
+];
+
+/// Resolve the name of a metamethod (with or without the leading underscores),
+/// returning an expression for the corresponding `mlua::MetaMethod`.
+pub fn resolve(name: &str, span: proc_macro2::Span) -> Result<TokenStream, darling::Error> {
+    let short_name = name.strip_prefix("__").unwrap_or(name);
+    if KNOWN_METAMETHODS.contains(&short_name) {
+        // NOTE: Go through a string, because variants depend on the lua version
+        let full_name = format!("__{}", short_name);
+        Ok(quote!(mlua::MetaMethod::from(String::from(#full_name))))
+    } else {
+        Err(darling::Error::custom(format!("Unknown metamethod {:?}", name)).with_span(&span))
+    }
+}
+
+/// Generate metamethods from rust trait implementations,
+/// as requested by `derive(Display, PartialEq, ...)`.
+///
+/// This expects `methods` to be in scope.
+pub fn expand_derives(derives: &darling::util::PathList) -> Result<TokenStream, darling::Error> {
+    let mut errors = Vec::new();
+    let mut res = Vec::new();
+    let binary_op = |meta: TokenStream, op_trait: TokenStream| {
+        quote! {
+            methods.add_meta_function(
+                mlua::MetaMethod::#meta,
+                |_, (a, b): (mlua::AnyUserData, mlua::AnyUserData)| {
+                    let a: Self = Clone::clone(&*a.borrow::<Self>()?);
+                    let b: Self = Clone::clone(&*b.borrow::<Self>()?);
+                    Ok(#op_trait(a, b))
+                },
+            );
+        }
+    };
+    for path in derives.iter() {
+        let name = match path.get_ident() {
+            Some(ident) => ident.to_string(),
+            None => String::new(),
+        };
+        res.push(match &*name {
+            "Display" => quote_spanned! { path.span() =>
+                methods.add_meta_method(
+                    mlua::MetaMethod::ToString,
+                    |_, this, ()| Ok(std::string::ToString::to_string(this)),
+                );
+            },
+            "PartialEq" => quote_spanned! { path.span() =>
+                methods.add_meta_function(
+                    mlua::MetaMethod::Eq,
+                    |_, (a, b): (mlua::AnyUserData, mlua::AnyUserData)| {
+                        Ok(match (a.borrow::<Self>(), b.borrow::<Self>()) {
+                            (Ok(a), Ok(b)) => PartialEq::eq(&*a, &*b),
+                            // Different types are never equal
+                            _ => false,
+                        })
+                    },
+                );
+            },
+            "PartialOrd" => quote_spanned! { path.span() =>
+                methods.add_meta_function(
+                    mlua::MetaMethod::Lt,
+                    |_, (a, b): (mlua::AnyUserData, mlua::AnyUserData)| {
+                        Ok(PartialOrd::lt(&*a.borrow::<Self>()?, &*b.borrow::<Self>()?))
+                    },
+                );
+                methods.add_meta_function(
+                    mlua::MetaMethod::Le,
+                    |_, (a, b): (mlua::AnyUserData, mlua::AnyUserData)| {
+                        Ok(PartialOrd::le(&*a.borrow::<Self>()?, &*b.borrow::<Self>()?))
+                    },
+                );
+            },
+            "Add" => binary_op(quote!(Add), quote!(std::ops::Add::add)),
+            "Sub" => binary_op(quote!(Sub), quote!(std::ops::Sub::sub)),
+            "Mul" => binary_op(quote!(Mul), quote!(std::ops::Mul::mul)),
+            "Div" => binary_op(quote!(Div), quote!(std::ops::Div::div)),
+            "Rem" => binary_op(quote!(Mod), quote!(std::ops::Rem::rem)),
+            "Neg" => quote_spanned! { path.span() =>
+                methods.add_meta_method(
+                    mlua::MetaMethod::Unm,
+                    |_, this, ()| Ok(std::ops::Neg::neg(Clone::clone(this))),
+                );
+            },
+            _ => {
+                errors.push(
+                    darling::Error::custom(
+                        "Expected one of Display, PartialEq, PartialOrd, Add, Sub, Mul, Div, Rem or Neg",
+                    )
+                    .with_span(path),
+                );
+                continue;
+            }
+        });
+    }
+    if !errors.is_empty() {
+        return Err(darling::Error::multiple(errors));
+    }
+    Ok(quote!(#(#res)*))
+}
@@ -0,0 +1,231 @@
+use darling::FromMeta;
+use proc_macro2::TokenStream;
+use quote::quote;
+
+use crate::func::{self, ReceiverKind, ReturnConvention, Wrapper, WrapperOptions};
+use crate::metamethods;
+use crate::utils::take_lua_attrs;
+
+#[derive(FromMeta, Debug)]
+pub struct LuaMethodsMeta {
+    /// Whether methods catch panics by default (default true)
+    ///
+    /// Associated functions in a module's class table default to the module's options instead.
+    #[darling(default)]
+    catch_panic: Option<bool>,
+    /// Capture backtraces for panics in methods (default false)
+    ///
+    /// Associated functions in a module's class table use the module's options instead.
+    #[darling(default)]
+    panic_backtraces: Option<bool>,
+    /// Whether to implement `mlua::UserData` (default true)
+    ///
+    /// Disable this if you want to implement `UserData` yourself,
+    /// calling `LuaMethods::add_lua_methods` to register the methods.
+    #[darling(default)]
+    userdata: Option<bool>,
+    /// Metamethods to generate from rust traits, like `derive(Display, PartialEq)`
+    #[darling(default)]
+    derive: darling::util::PathList,
+}
+
+/// The `#[lua(...)]` attribute on an individual method
+#[derive(FromMeta, Debug, Default)]
+struct LuaMethodMeta {
+    /// Override the name of the method in lua
+    #[darling(default)]
+    name: Option<String>,
+    /// Don't expose this method to lua
+    #[darling(default)]
+    skip: darling::util::Flag,
+    /// Register this method as a metamethod (like "tostring" or "__index")
+    #[darling(default)]
+    meta: Option<String>,
+    #[darling(default)]
+    returns: ReturnConvention,
+    #[darling(default)]
+    error_code: Option<syn::Path>,
+    #[darling(default)]
+    catch_panic: Option<bool>,
+}
+
+pub fn expand(meta: LuaMethodsMeta, item: syn::Item) -> Result<TokenStream, darling::Error> {
+    let mut item = match item {
+        syn::Item::Impl(item) => item,
+        _ => {
+            return Err(darling::Error::custom(
+                "Expected an impl block (`impl Foo { ... }`)",
+            ))
+        }
+    };
+    if let Some((_, ref trait_path, _)) = item.trait_ {
+        return Err(
+            darling::Error::custom("Expected an inherent impl, not a trait impl")
+                .with_span(trait_path),
+        );
+    }
+    let default_catch_panic = meta.catch_panic.unwrap_or(true);
+    let capture_backtrace = meta.panic_backtraces.unwrap_or(false);
+    let mut errors = Vec::new();
+    let mut registrations = Vec::new();
+    let mut static_functions = Vec::new();
+    for impl_item in item.items.iter_mut() {
+        let method = match *impl_item {
+            syn::ImplItem::Method(ref mut method) => method,
+            _ => continue,
+        };
+        let method_meta = match take_lua_attrs::<LuaMethodMeta>(&mut method.attrs) {
+            Ok(meta) => meta,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        if method_meta.skip.is_some() {
+            continue;
+        }
+        let wrapper = match Wrapper::from_signature(
+            &method.sig,
+            WrapperOptions {
+                returns: method_meta.returns,
+                error_code: method_meta.error_code,
+            },
+        ) {
+            Ok(wrapper) => wrapper,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        if wrapper.is_stateful() {
+            errors.push(
+                darling::Error::custom("State parameters are only supported by #[lua_function]")
+                    .with_span(&method.sig.inputs),
+            );
+            continue;
+        }
+        let name = &method.sig.ident;
+        let metamethod = match method_meta.meta {
+            Some(ref meta) => match metamethods::resolve(meta, name.span()) {
+                Ok(resolved) => Some(resolved),
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let lua_name = match (method_meta.name, method_meta.meta) {
+            (Some(name), _) => name,
+            (None, Some(meta)) if meta.starts_with("__") => meta,
+            (None, Some(meta)) => format!("__{}", meta),
+            (None, None) => name.to_string(),
+        };
+        let call_body = wrapper.call_body(&quote!(#lua_name), &quote!(Self::#name));
+        let body = if method_meta.catch_panic.unwrap_or(default_catch_panic) {
+            quote!(luao3::panic::catch_panic(#lua_name, #capture_backtrace, || { #call_body }))
+        } else {
+            call_body.clone()
+        };
+        let body = quote! {
+            let __args = luao3::parse_helpers::FunctionArgs::new(lua, #lua_name, __args);
+            #body
+        };
+        let closure = match wrapper.receiver() {
+            Some(_) => quote!(|lua, this, __args: mlua::MultiValue| { #body }),
+            None => quote!(|lua, __args: mlua::MultiValue| { #body }),
+        };
+        registrations.push(match (wrapper.receiver(), metamethod) {
+            (Some(ReceiverKind::Ref), Some(meta)) => quote! {
+                methods.add_meta_method(#meta, #closure);
+            },
+            (Some(ReceiverKind::Mut), Some(meta)) => quote! {
+                methods.add_meta_method_mut(#meta, #closure);
+            },
+            (None, Some(meta)) => quote! {
+                methods.add_meta_function(#meta, #closure);
+            },
+            (Some(ReceiverKind::Ref), None) => quote! {
+                methods.add_method(#lua_name, #closure);
+            },
+            (Some(ReceiverKind::Mut), None) => quote! {
+                methods.add_method_mut(#lua_name, #closure);
+            },
+            (None, None) => {
+                // Class tables belong to a module, so they follow its options (like `create_with`)
+                let catch_panic = match method_meta.catch_panic.or(meta.catch_panic) {
+                    Some(catch_panic) => quote!(#catch_panic),
+                    None => quote!(options.catch_panics),
+                };
+                static_functions.push(quote! {
+                    table.set(#lua_name, {
+                        let catch_panic = #catch_panic;
+                        let capture_backtrace = options.capture_backtrace;
+                        lua.create_function(move |lua, __args: mlua::MultiValue| {
+                            let __args = luao3::parse_helpers::FunctionArgs::new(lua, #lua_name, __args);
+                            #[allow(unused_mut)]
+                            let mut call = || { #call_body };
+                            if catch_panic {
+                                luao3::panic::catch_panic(#lua_name, capture_backtrace, call)
+                            } else {
+                                call()
+                            }
+                        })?
+                    })?;
+                });
+                quote! {
+                    methods.add_function(#lua_name, #closure);
+                }
+            }
+        });
+        func::strip_param_attrs(&mut method.sig);
+    }
+    match metamethods::expand_derives(&meta.derive) {
+        Ok(derived) => registrations.push(derived),
+        Err(e) => errors.push(e),
+    }
+    if !errors.is_empty() {
+        return Err(darling::Error::multiple(errors));
+    }
+    let self_ty = &item.self_ty;
+    let class_name = match **self_ty {
+        syn::Type::Path(ref path) if path.qself.is_none() => {
+            path.path.segments.last().unwrap().ident.to_string()
+        }
+        ref other => quote!(#other).to_string(),
+    };
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    let userdata_impl = if meta.userdata.unwrap_or(true) {
+        Some(quote! {
+            impl #impl_generics mlua::UserData for #self_ty #where_clause {
+                fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+                    luao3::userdata::add_class_name::<Self, F>(fields)
+                }
+                fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+                    <Self as luao3::userdata::LuaMethods>::add_lua_methods(methods)
+                }
+            }
+        })
+    } else {
+        None
+    };
+    Ok(quote! {
+        #item
+        impl #impl_generics luao3::userdata::LuaMethods for #self_ty #where_clause {
+            const CLASS_NAME: &'static str = #class_name;
+            fn add_lua_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+                #(#registrations)*
+            }
+            #[allow(unused_variables)]
+            fn add_static_functions<'lua>(
+                lua: &'lua mlua::Lua,
+                table: &mlua::Table<'lua>,
+                options: &luao3::FunctionOptions,
+            ) -> mlua::Result<()> {
+                #(#static_functions)*
+                Ok(())
+            }
+        }
+        #userdata_impl
+    })
+}
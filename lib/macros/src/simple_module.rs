@@ -2,7 +2,7 @@ use indexmap::map::Entry;
 use indexmap::IndexMap;
 use proc_macro2::{Ident, Span, TokenStream};
 use proc_macro_kwargs::{parse_macro_arg_via_syn, MacroKeywordArgs};
-use quote::{quote, quote_spanned};
+use quote::{format_ident, quote, quote_spanned};
 use syn::parse::{Parse, ParseBuffer, ParseStream};
 use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
@@ -12,35 +12,142 @@ use crate::utils;
 
 #[derive(MacroKeywordArgs)]
 pub struct ModuleArgs {
-    name: Ident,
-    members: ModuleMembers,
+    pub name: Ident,
+    pub members: ModuleMembers,
+    /// The visibility of the generated function (default private)
+    #[kwarg(optional)]
+    pub vis: Option<syn::Visibility>,
+    /// Convert panics in functions into lua errors (default true)
+    #[kwarg(optional)]
+    pub catch_panics: Option<syn::LitBool>,
+    /// Capture backtraces for panics (default false, requires `luao3::panic::install_hook`)
+    #[kwarg(optional)]
+    pub panic_backtraces: Option<syn::LitBool>,
+    /// State shared by all the functions in the module,
+    /// which is constructed once each time the module is created
+    #[kwarg(optional)]
+    pub state: Option<syn::Expr>,
+    /// Also emit a `luaopen_<name>` entry point,
+    /// so the module can be loaded from a shared library with `require` (default false)
+    ///
+    /// This requires the `module` feature.
+    #[kwarg(optional)]
+    pub entry_point: Option<syn::LitBool>,
+    /// Documentation for the module, available through `LuaModule::DOC`
+    #[kwarg(optional)]
+    pub doc: Option<syn::LitStr>,
+    /// Only create each member when it is first accessed (default false)
+    ///
+    /// This speeds up loading modules with many members.
+    #[kwarg(optional)]
+    pub lazy: Option<syn::LitBool>,
 }
 
 pub fn expand_module(args: ModuleArgs) -> Result<TokenStream, Error> {
     let name = &args.name;
-    let reserved_names = &["lua", "res"];
-    let members_decl = utils::collect_vec_combining_errors(
-        args.members.items.iter().map(|(name, item)| {
-            let declared = item.item.declare(&|ident: &Ident| {
-                reserved_names.iter().any(|reserved| ident == *reserved)
-            })?;
-            let name_expr = name.string_expr();
-            Ok(quote!(res.set(
-                #name_expr,
-                #declared
-            )))
-        }),
-        utils::combine_syn_errors,
-    )?;
+    let vis = &args.vis;
+    let lazy = matches!(args.lazy, Some(ref val) if val.value);
+    let mut values = ValueHelpers {
+        module: name.clone(),
+        items: Vec::new(),
+    };
+    let members = args.members.expand(lazy, &mut values)?;
+    let value_items = &values.items;
+    let members_metadata = args.members.metadata();
+    let (doc_attr, doc_const) = match args.doc {
+        Some(ref doc) => (
+            Some(quote!(#[doc = #doc])),
+            Some(quote!(const DOC: Option<&'static str> = Some(#doc);)),
+        ),
+        None => (None, None),
+    };
+    let catch_panics = args
+        .catch_panics
+        .as_ref()
+        .map(|val| quote!(catch_panics: #val,));
+    let panic_backtraces = args
+        .panic_backtraces
+        .as_ref()
+        .map(|val| quote!(capture_backtrace: #val,));
+    let module_state = args
+        .state
+        .as_ref()
+        .map(|val| quote!(module_state: Some(luao3::func::ModuleState::new(#val)),));
+    let entry_point = match args.entry_point {
+        Some(ref val) if val.value => {
+            let entry_point_name = Ident::new(&format!("luaopen_{}", name), name.span());
+            Some(quote_spanned! { val.span() =>
+                mlua::require_module_feature!();
+                /// The entry point used by lua to load the module from a shared library
+                #[no_mangle]
+                #[doc(hidden)]
+                pub unsafe extern "C" fn #entry_point_name(
+                    state: *mut mlua::lua_State,
+                ) -> std::os::raw::c_int {
+                    mlua::Lua::init_from_ptr(state)
+                        .entrypoint1(#name)
+                        .expect("cannot initialize module")
+                }
+            })
+        }
+        _ => None,
+    };
     Ok(quote! {
-        fn #name<'lua>(lua: &'lua mlua::Lua) -> mlua::Result<mlua::Table<'lua>> {
-            let res = lua.create_table()?;
-            #(#members_decl ;)*
-            Ok(res)
+        #entry_point
+        #[doc(hidden)]
+        #[allow(non_camel_case_types, dead_code)]
+        #vis struct #name {}
+        impl luao3::LuaModule for #name {
+            const NAME: &'static str = stringify!(#name);
+            #doc_const
+            const MEMBERS: &'static [luao3::module::ModuleMember] = #members_metadata;
+            #[inline]
+            fn create(lua: &mlua::Lua) -> mlua::Result<mlua::Table<'_>> {
+                #name(lua)
+            }
+        }
+        impl #name {
+            #(#value_items)*
+        }
+        luao3::__register_module!(#name);
+        #doc_attr
+        #vis fn #name<'lua>(lua: &'lua mlua::Lua) -> mlua::Result<mlua::Table<'lua>> {
+            // NOTE: Modules without any functions don't use the options
+            #[allow(unused_variables, clippy::needless_update)]
+            let options = luao3::FunctionOptions {
+                #catch_panics
+                #panic_backtraces
+                #module_state
+                ..Default::default()
+            };
+            Ok(#members)
         }
     })
 }
 
+/// The functions that evaluate `value name = ...` members,
+/// which are associated with the module's marker type.
+///
+/// This keeps the values from seeing the variables used to build the module
+/// (like `lua`, `res` or `options`).
+pub struct ValueHelpers {
+    module: Ident,
+    items: Vec<TokenStream>,
+}
+impl ValueHelpers {
+    /// Add a helper function for the specified value, returning an expression that calls it
+    fn add(&mut self, value: &Expr) -> TokenStream {
+        let helper = format_ident!("value{}", self.items.len());
+        self.items.push(quote_spanned! { value.span() =>
+            fn #helper<'lua>() -> impl mlua::ToLua<'lua> {
+                #value
+            }
+        });
+        let module = &self.module;
+        quote_spanned!(value.span() => #module::#helper())
+    }
+}
+
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub enum LuaName {
     Ident(Ident),
@@ -82,16 +189,169 @@ impl Parse for LuaName {
 
 pub struct ModuleMembers {
     items: IndexMap<LuaName, ModuleItemDecl>,
+    /// Lua files declared with `lua "file.lua"`,
+    /// which are run after all the other members are created.
+    chunks: Vec<syn::LitStr>,
 }
 parse_macro_arg_via_syn!(ModuleMembers);
+impl ModuleMembers {
+    /// Generate an expression that creates a table containing the members
+    ///
+    /// If `lazy` is true, members (including nested ones) are only created on first access.
+    ///
+    /// This expects `lua` and `options` to be in scope.
+    fn expand(&self, lazy: bool, values: &mut ValueHelpers) -> Result<TokenStream, Error> {
+        let members_decl = utils::collect_vec_combining_errors(
+            self.items.iter().map(|(name, item)| {
+                let declared = item.item.declare(lazy, values)?;
+                let name_expr = name.string_expr();
+                Ok(if lazy {
+                    quote!(luao3::module::LazyMember::new(#name_expr, {
+                        #[allow(unused_variables)]
+                        let options = options.clone();
+                        move |lua| mlua::ToLua::to_lua(#declared, lua)
+                    }))
+                } else {
+                    quote!(res.set(
+                        #name_expr,
+                        #declared
+                    )?)
+                })
+            }),
+            utils::combine_syn_errors,
+        )?;
+        let chunks = utils::collect_vec_combining_errors(
+            self.chunks.iter().map(|path| {
+                let chunk = embed_chunk(path)?;
+                Ok(quote_spanned! { path.span() =>
+                    luao3::module::run_embedded_chunk(lua, &res, #path, #chunk)?
+                })
+            }),
+            utils::combine_syn_errors,
+        )?;
+        if lazy {
+            Ok(quote!({
+                let res = luao3::module::lazy_table(lua, vec![#(#members_decl),*])?;
+                #(#chunks ;)*
+                res
+            }))
+        } else {
+            Ok(quote!({
+                let res = lua.create_table()?;
+                #(#members_decl ;)*
+                #(#chunks ;)*
+                res
+            }))
+        }
+    }
+    /// Generate an expression describing the members,
+    /// of type `&'static [luao3::module::ModuleMember]`.
+    ///
+    /// Names are only checked by `expand`, so this silently skips invalid ones.
+    fn metadata(&self) -> TokenStream {
+        let members = self.items.iter().map(|(name, item)| {
+            let name_expr = name.string_expr();
+            let kind = item.item.metadata();
+            let doc = match item.doc {
+                Some(ref doc) => quote!(Some(#doc)),
+                None => quote!(None),
+            };
+            quote! {
+                luao3::module::ModuleMember {
+                    name: #name_expr,
+                    kind: #kind,
+                    doc: #doc,
+                }
+            }
+        });
+        quote!(&[#(#members),*])
+    }
+}
 impl Parse for ModuleMembers {
     fn parse(input: ParseStream) -> syn::Result<Self> {
         let items: ParseBuffer;
         braced!(items in input);
-        let items: Punctuated<ModuleItemDecl, Token![,]> = Punctuated::parse_terminated(&items)?;
-        let mut map: IndexMap<LuaName, ModuleItemDecl> = IndexMap::with_capacity(items.len());
+        let entries: Punctuated<ModuleEntry, Token![,]> = Punctuated::parse_terminated(&items)?;
+        let mut decls = Vec::with_capacity(entries.len());
+        let mut chunks = Vec::new();
+        for entry in entries {
+            match entry {
+                ModuleEntry::Item(decl) => decls.push(*decl),
+                ModuleEntry::Chunk(path) => chunks.push(path),
+            }
+        }
+        let mut members = ModuleMembers::from_decls(decls)?;
+        members.chunks = chunks;
+        Ok(members)
+    }
+}
+
+/// Either a member or a lua file, declared with `lua "file.lua"`
+enum ModuleEntry {
+    Item(Box<ModuleItemDecl>),
+    Chunk(syn::LitStr),
+}
+impl Parse for ModuleEntry {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(syn::Ident)
+            && input.peek2(syn::LitStr)
+            && input.fork().parse::<Ident>()? == "lua"
+        {
+            input.parse::<Ident>()?;
+            Ok(ModuleEntry::Chunk(input.parse()?))
+        } else {
+            Ok(ModuleEntry::Item(Box::new(input.parse()?)))
+        }
+    }
+}
+
+/// Generate an expression for the `luao3::module::EmbeddedChunk` at the specified path,
+/// relative to the root of the crate.
+///
+/// The syntax is always checked, by compiling the chunk with the vendored lua in the macros.
+/// With the `precompile` feature, the bytecode is also stored.
+fn embed_chunk(path: &syn::LitStr) -> Result<TokenStream, Error> {
+    let include = quote_spanned! { path.span() =>
+        include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/", #path))
+    };
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR")
+        .map_err(|_| Error::new(path.span(), "Missing $CARGO_MANIFEST_DIR"))?;
+    let full_path = std::path::Path::new(&manifest_dir).join(path.value());
+    let source = std::fs::read_to_string(&full_path).map_err(|e| {
+        Error::new(
+            path.span(),
+            format!("Unable to read {}: {}", full_path.display(), e),
+        )
+    })?;
+    let lua = mlua::Lua::new();
+    // NOTE: This only compiles the chunk, it is never run
+    let func = lua
+        .load(&source)
+        .set_name(&format!("@{}", path.value()))
+        .and_then(|chunk| chunk.into_function())
+        .map_err(|e| Error::new(path.span(), format!("Invalid lua code: {}", e)))?;
+    if cfg!(feature = "precompile") {
+        let bytecode = syn::LitByteStr::new(&func.dump(false), path.span());
+        Ok(quote_spanned! { path.span() =>
+            luao3::module::EmbeddedChunk::Precompiled {
+                bytecode: #bytecode,
+                source: #include,
+            }
+        })
+    } else {
+        Ok(quote_spanned! { path.span() =>
+            luao3::module::EmbeddedChunk::Source(#include)
+        })
+    }
+}
+impl ModuleMembers {
+    /// Collect the specified declarations, checking for conflicting names
+    pub fn from_decls(decls: impl IntoIterator<Item = ModuleItemDecl>) -> syn::Result<Self> {
+        let decls = decls.into_iter();
+        let mut map: IndexMap<LuaName, ModuleItemDecl> =
+            IndexMap::with_capacity(decls.size_hint().0);
         let mut errors: Vec<syn::Error> = Vec::new();
-        for item in items {
+        for item in decls {
             error_loop!(errors, Result<(), syn::Error>; {
                 let name = item.name()?;
                 match map.entry(name.clone()) {
@@ -109,12 +369,17 @@ impl Parse for ModuleMembers {
             })
         }
         utils::combine_syn_errors(errors)?;
-        Ok(ModuleMembers { items: map })
+        Ok(ModuleMembers {
+            items: map,
+            chunks: Vec::new(),
+        })
     }
 }
-struct ModuleItemDecl {
-    custom_name: Option<LuaName>,
-    item: ModuleItem,
+pub struct ModuleItemDecl {
+    pub custom_name: Option<LuaName>,
+    pub item: ModuleItem,
+    /// The doc comments on the item, joined into a single string
+    pub doc: Option<String>,
 }
 
 impl ModuleItemDecl {
@@ -128,6 +393,13 @@ impl ModuleItemDecl {
 
 impl Parse for ModuleItemDecl {
     fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(syn::Attribute::parse_outer)?;
+        if let Some(attr) = attrs.iter().find(|attr| !attr.path.is_ident("doc")) {
+            return Err(Error::new(
+                attr.span(),
+                "Only doc comments are supported on module members",
+            ));
+        }
         let item: ModuleItem = input.parse()?;
         let custom_name = if input.peek(Token![as]) {
             input.parse::<Token![as]>()?;
@@ -135,27 +407,128 @@ impl Parse for ModuleItemDecl {
         } else {
             None
         };
-        Ok(ModuleItemDecl { item, custom_name })
+        Ok(ModuleItemDecl {
+            item,
+            custom_name,
+            doc: utils::doc_string(&attrs),
+        })
     }
 }
 
-enum ModuleItem {
-    Function { path: Path },
+pub enum ModuleItem {
+    Function {
+        path: Path,
+    },
+    /// A value converted with `ToLua` when the module is created,
+    /// declared with either `const NAME = expr` or `value name = expr`
+    Value {
+        name: Ident,
+        value: Expr,
+    },
+    /// A table with the associated functions of a type implementing `luao3::userdata::LuaMethods`,
+    /// declared with either `class Foo` or `type Foo`
+    Class {
+        path: Path,
+    },
+    /// A table with the unit variants of an enum implementing `luao3::LuaEnum`
+    /// (like `#[derive(ToLua)]` with `#[lua(constants)]`)
+    Enum {
+        path: Path,
+    },
+    /// A nested table of members, declared with `mod name { ... }`
+    Submodule {
+        name: Ident,
+        members: ModuleMembers,
+    },
+    /// Another module declared with `declare_simple_module!`
+    Module {
+        path: Path,
+    },
+    /// Several functions sharing a single lua name,
+    /// chosen at runtime based on the types of the arguments.
+    Overload {
+        name: Ident,
+        candidates: Punctuated<Path, Token![,]>,
+    },
 }
 impl ModuleItem {
-    pub fn declare(&self, reserved: &dyn Fn(&Ident) -> bool) -> Result<TokenStream, Error> {
-        let qualify = |p: &Path| {
-            if let Some(ident) = p.get_ident().filter(|i| reserved(*i)) {
-                syn::parse_quote!(self::#ident)
-            } else {
-                p.clone()
+    pub fn declare(&self, lazy: bool, values: &mut ValueHelpers) -> Result<TokenStream, Error> {
+        match *self {
+            ModuleItem::Function { ref path } => Ok(quote_spanned! { path.span() =>
+                <#path as luao3::LuaFunction>::create_with(lua, &options)?
+            }),
+            ModuleItem::Value { ref value, .. } => {
+                let helper = values.add(value);
+                Ok(quote_spanned! { value.span() =>
+                    mlua::ToLua::to_lua(#helper, lua)?
+                })
             }
-        };
+            ModuleItem::Enum { ref path } => Ok(quote_spanned! { path.span() =>
+                <#path as luao3::LuaEnum>::constants_table(lua)?
+            }),
+            ModuleItem::Class { ref path } => Ok(quote_spanned! { path.span() =>
+                luao3::userdata::class_table::<#path>(lua, &options)?
+            }),
+            ModuleItem::Submodule { ref members, .. } => members.expand(lazy, values),
+            ModuleItem::Module { ref path } => Ok(quote_spanned! { path.span() =>
+                #path(lua)?
+            }),
+            ModuleItem::Overload {
+                ref name,
+                ref candidates,
+            } => {
+                if candidates.is_empty() {
+                    return Err(Error::new(name.span(), "Expected at least one overload"));
+                }
+                let candidates = candidates.iter().map(
+                    |path| quote_spanned!(path.span() => luao3::func::Overload::of::<#path>()),
+                );
+                Ok(quote_spanned! { name.span() =>
+                    luao3::func::create_overloaded(
+                        lua,
+                        stringify!(#name),
+                        &options,
+                        vec![#(#candidates),*],
+                    )?
+                })
+            }
+        }
+    }
+    /// Generate an expression for the corresponding `luao3::module::MemberKind`
+    fn metadata(&self) -> TokenStream {
         match *self {
-            ModuleItem::Function { ref path } => {
-                let path = qualify(path);
-                Ok(quote_spanned! { path.span() =>
-                    lua.create_function(#path)?
+            ModuleItem::Function { ref path } => quote_spanned! { path.span() =>
+                luao3::module::MemberKind::Function {
+                    signature: <#path as luao3::LuaFunction>::SIGNATURE,
+                }
+            },
+            ModuleItem::Value { .. } => quote!(luao3::module::MemberKind::Value),
+            ModuleItem::Class { ref path } => quote_spanned! { path.span() =>
+                luao3::module::MemberKind::Class {
+                    class_name: <#path as luao3::userdata::LuaMethods>::CLASS_NAME,
+                }
+            },
+            ModuleItem::Enum { ref path } => quote_spanned! { path.span() =>
+                luao3::module::MemberKind::Enum {
+                    variants: <#path as luao3::LuaEnum>::VARIANTS,
+                }
+            },
+            ModuleItem::Submodule { ref members, .. } => {
+                let members = members.metadata();
+                quote!(luao3::module::MemberKind::Submodule { members: #members })
+            }
+            ModuleItem::Module { ref path } => quote_spanned! { path.span() =>
+                luao3::module::MemberKind::Module {
+                    name: <#path as luao3::LuaModule>::NAME,
+                    members: <#path as luao3::LuaModule>::MEMBERS,
+                }
+            },
+            ModuleItem::Overload { ref candidates, .. } => {
+                let signatures = candidates.iter().map(
+                    |path| quote_spanned!(path.span() => <#path as luao3::LuaFunction>::SIGNATURE),
+                );
+                quote!(luao3::module::MemberKind::Overload {
+                    signatures: &[#(#signatures),*],
                 })
             }
         }
@@ -163,16 +536,28 @@ impl ModuleItem {
     #[inline]
     fn implicit_name(&self) -> Option<&Ident> {
         match *self {
-            ModuleItem::Function { ref path, .. } => {
+            ModuleItem::Function { ref path, .. }
+            | ModuleItem::Enum { ref path }
+            | ModuleItem::Class { ref path }
+            | ModuleItem::Module { ref path } => {
                 // Pretty sure path must be nonempty
                 Some(&path.segments.last().unwrap().ident)
             }
+            ModuleItem::Value { ref name, .. }
+            | ModuleItem::Submodule { ref name, .. }
+            | ModuleItem::Overload { ref name, .. } => Some(name),
         }
     }
     #[inline]
     fn decl_span(&self) -> Span {
         match *self {
-            ModuleItem::Function { ref path, .. } => path.span(),
+            ModuleItem::Function { ref path, .. }
+            | ModuleItem::Enum { ref path }
+            | ModuleItem::Class { ref path }
+            | ModuleItem::Module { ref path } => path.span(),
+            ModuleItem::Value { ref name, .. }
+            | ModuleItem::Submodule { ref name, .. }
+            | ModuleItem::Overload { ref name, .. } => name.span(),
         }
     }
 }
@@ -183,6 +568,49 @@ impl Parse for ModuleItem {
             input.parse::<Token![fn]>()?;
             let path = input.parse::<Path>()?;
             Ok(ModuleItem::Function { path })
+        } else if input.peek(Token![const])
+            || (input.peek(syn::Ident) && input.fork().parse::<Ident>()? == "value")
+        {
+            // NOTE: `const` and `value` are equivalent, but one reads better for literals
+            if input.peek(Token![const]) {
+                input.parse::<Token![const]>()?;
+            } else {
+                input.parse::<Ident>()?;
+            }
+            let name = input.parse::<Ident>()?;
+            input.parse::<Token![=]>()?;
+            let value = input.parse::<Expr>()?;
+            Ok(ModuleItem::Value { name, value })
+        } else if input.peek(Token![type])
+            || (input.peek(syn::Ident) && input.fork().parse::<Ident>()? == "class")
+        {
+            if input.peek(Token![type]) {
+                input.parse::<Token![type]>()?;
+            } else {
+                input.parse::<Ident>()?;
+            }
+            let path = input.parse::<Path>()?;
+            Ok(ModuleItem::Class { path })
+        } else if input.peek(Token![mod]) {
+            input.parse::<Token![mod]>()?;
+            let name = input.parse::<Ident>()?;
+            let members = input.parse::<ModuleMembers>()?;
+            Ok(ModuleItem::Submodule { name, members })
+        } else if input.peek(syn::Ident) && input.fork().parse::<Ident>()? == "module" {
+            input.parse::<Ident>()?;
+            let path = input.parse::<Path>()?;
+            Ok(ModuleItem::Module { path })
+        } else if input.peek(Token![enum]) {
+            input.parse::<Token![enum]>()?;
+            let path = input.parse::<Path>()?;
+            Ok(ModuleItem::Enum { path })
+        } else if input.peek(syn::Ident) && input.fork().parse::<Ident>()? == "overload" {
+            input.parse::<Ident>()?;
+            let name = input.parse::<Ident>()?;
+            let candidates: ParseBuffer;
+            braced!(candidates in input);
+            let candidates = Punctuated::parse_terminated(&candidates)?;
+            Ok(ModuleItem::Overload { name, candidates })
         } else {
             Err(input.error("Unexpected token for module item"))
         }
@@ -39,3 +39,78 @@ pub fn collect_vec_combining_errors<T, E>(
     error_combiner(errors)?;
     Ok(success)
 }
+
+/// Check if the type is a `luao3::Callback`
+///
+/// Like the check for `Result`, this is purely syntactic.
+pub fn is_callback_type(ty: &syn::Type) -> bool {
+    match *ty {
+        syn::Type::Path(ref path) => {
+            matches!(path.path.segments.last(), Some(last) if last.ident == "Callback")
+        }
+        _ => false,
+    }
+}
+
+/// Join the doc comments in the specified attributes into a single string,
+/// returning `None` if there are none.
+///
+/// Other attributes are ignored.
+pub fn doc_string(attrs: &[syn::Attribute]) -> Option<String> {
+    let lines = attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("doc"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(syn::Meta::NameValue(syn::MetaNameValue {
+                lit: syn::Lit::Str(text),
+                ..
+            })) => {
+                let text = text.value();
+                // Strip the space following `///`
+                Some(text.strip_prefix(' ').unwrap_or(&text).to_string())
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\n"))
+    }
+}
+
+/// Parse the `#[lua(...)]` attributes from the specified list
+pub fn parse_lua_attrs<T: darling::FromMeta + Default>(
+    attrs: &[syn::Attribute],
+) -> Result<T, darling::Error> {
+    let mut nested = Vec::new();
+    let mut errors = Vec::new();
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("lua")) {
+        match attr.parse_meta() {
+            Ok(syn::Meta::List(list)) => nested.extend(list.nested),
+            // A plain `#[lua]` just marks the item, without any options
+            Ok(syn::Meta::Path(_)) => {}
+            Ok(other) => {
+                errors.push(darling::Error::custom("Expected `#[lua(...)]`").with_span(&other))
+            }
+            Err(e) => errors.push(e.into()),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(darling::Error::multiple(errors));
+    }
+    if nested.is_empty() {
+        Ok(T::default())
+    } else {
+        T::from_list(&nested)
+    }
+}
+
+/// Remove the `#[lua(...)]` attributes from the specified list, parsing them
+pub fn take_lua_attrs<T: darling::FromMeta + Default>(
+    attrs: &mut Vec<syn::Attribute>,
+) -> Result<T, darling::Error> {
+    let res = parse_lua_attrs(attrs);
+    attrs.retain(|attr| !attr.path.is_ident("lua"));
+    res
+}
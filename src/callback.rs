@@ -0,0 +1,125 @@
+//! Typed lua callbacks
+
+use std::fmt::{self, Debug, Display, Formatter};
+use std::marker::PhantomData;
+
+use mlua::{FromLua, FromLuaMulti, Function, Lua, MultiValue, ToLua, ToLuaMulti, Value};
+
+use crate::parse_helpers::{conversion_detail, TableKey};
+
+/// A lua function, which is called with arguments of type `Args`
+/// and is expected to return a `Ret`.
+///
+/// This can be used as a `#[lua_function]` parameter (or a field of a struct
+/// deriving `FromLua`) in place of an untyped `mlua::Function`.
+/// If the callback returns the wrong thing, the error says where the
+/// callback came from (like "argument #2 to 'map'").
+pub struct Callback<'lua, Args, Ret> {
+    lua: &'lua Lua,
+    func: Function<'lua>,
+    origin: CallbackOrigin,
+    marker: PhantomData<fn(Args) -> Ret>,
+}
+impl<'lua, Args, Ret> Callback<'lua, Args, Ret> {
+    /// Wrap an untyped lua function
+    #[inline]
+    pub fn new(lua: &'lua Lua, func: Function<'lua>) -> Self {
+        Callback {
+            lua,
+            func,
+            origin: CallbackOrigin::Unknown,
+            marker: PhantomData,
+        }
+    }
+    /// The underlying lua function
+    #[inline]
+    pub fn function(&self) -> &Function<'lua> {
+        &self.func
+    }
+    /// Convert into the underlying lua function
+    #[inline]
+    pub fn into_function(self) -> Function<'lua> {
+        self.func
+    }
+    #[inline]
+    pub(crate) fn with_origin(mut self, origin: CallbackOrigin) -> Self {
+        self.origin = origin;
+        self
+    }
+}
+impl<'lua, Args: ToLuaMulti<'lua>, Ret: FromLuaMulti<'lua>> Callback<'lua, Args, Ret> {
+    /// Call the callback, converting the result to the expected type
+    pub fn call(&self, args: Args) -> mlua::Result<Ret> {
+        let results: MultiValue<'lua> = self.func.call(args)?;
+        Ret::from_lua_multi(results, self.lua).map_err(|cause| {
+            let detail = conversion_detail(&cause);
+            mlua::Error::RuntimeError(match self.origin {
+                CallbackOrigin::Unknown => format!("bad return value from callback: {detail}"),
+                ref origin => format!("bad return value from callback ({origin}): {detail}"),
+            })
+        })
+    }
+}
+impl<'lua, Args, Ret> Clone for Callback<'lua, Args, Ret> {
+    fn clone(&self) -> Self {
+        Callback {
+            lua: self.lua,
+            func: self.func.clone(),
+            origin: self.origin,
+            marker: PhantomData,
+        }
+    }
+}
+impl<'lua, Args, Ret> Debug for Callback<'lua, Args, Ret> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Callback")
+            .field("func", &self.func)
+            .field("origin", &self.origin)
+            .finish()
+    }
+}
+impl<'lua, Args, Ret> FromLua<'lua> for Callback<'lua, Args, Ret> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> mlua::Result<Self> {
+        match value {
+            Value::Function(func) => Ok(Callback::new(lua, func)),
+            other => Err(mlua::Error::FromLuaConversionError {
+                from: other.type_name(),
+                to: "function",
+                message: None,
+            }),
+        }
+    }
+}
+impl<'lua, Args, Ret> ToLua<'lua> for Callback<'lua, Args, Ret> {
+    #[inline]
+    fn to_lua(self, _lua: &'lua Lua) -> mlua::Result<Value<'lua>> {
+        Ok(Value::Function(self.func))
+    }
+}
+
+/// Where a callback came from, for use in error messages
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum CallbackOrigin {
+    Unknown,
+    Argument {
+        func_name: &'static str,
+        index: usize,
+    },
+    Field {
+        target_type: &'static str,
+        key: TableKey,
+    },
+}
+impl Display for CallbackOrigin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            CallbackOrigin::Unknown => f.write_str("unknown"),
+            CallbackOrigin::Argument { func_name, index } => {
+                write!(f, "argument #{index} to '{func_name}'")
+            }
+            CallbackOrigin::Field { target_type, key } => {
+                write!(f, "field {key} of {target_type}")
+            }
+        }
+    }
+}
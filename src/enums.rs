@@ -0,0 +1,21 @@
+//! Exposing the variants of rust enums to lua
+
+use mlua::{Lua, Table};
+
+/// An enum with unit variants, which are converted to lua as their names.
+///
+/// This is implemented by `#[derive(ToLua)]` with `#[lua(constants)]`,
+/// and allows `declare_simple_module!` to expose the variants as constants
+/// (like `mymod.Color.Red == "Red"`) using `enum Color`.
+pub trait LuaEnum {
+    /// The names of the unit variants
+    const VARIANTS: &'static [&'static str];
+    /// Create a table mapping each variant name to its lua value
+    fn constants_table(lua: &Lua) -> mlua::Result<Table<'_>> {
+        let table = lua.create_table()?;
+        for &name in Self::VARIANTS {
+            table.set(name, name)?;
+        }
+        Ok(table)
+    }
+}
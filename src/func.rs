@@ -0,0 +1,250 @@
+//! Contains the [`LuaFunction`] trait, which is implemented by `#[lua_function]`.
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt::{self, Debug, Formatter};
+use std::rc::Rc;
+
+use mlua::{Function, Lua, MultiValue};
+
+use crate::parse_helpers::{self, FunctionArgs};
+
+/// A rust function that can be called from lua.
+///
+/// The `#[lua_function]` attribute leaves the original function untouched,
+/// and implements this trait for a hidden marker type with the same name.
+/// Types and functions live in different namespaces,
+/// so `declare_simple_module!` can refer to both using the same path.
+pub trait LuaFunction: 'static {
+    /// The name of the function, as used in error messages.
+    const NAME: &'static str;
+    /// A description of the function's lua parameters, like `vec(x: f64, y: f64)`.
+    const SIGNATURE: &'static str = Self::NAME;
+    /// The number of lua parameters the function declares.
+    ///
+    /// Overloads with more parameters are considered more specific.
+    const PARAM_COUNT: usize = 0;
+    /// Whether this function catches panics,
+    /// overriding [`FunctionOptions::catch_panics`] if present.
+    const CATCH_PANIC: Option<bool> = None;
+    /// Whether the function keeps state outside of each call,
+    /// like `#[lua(state)]` or `#[lua(module_state)]` parameters.
+    ///
+    /// Stateful functions can only be invoked through the function object
+    /// created by [`LuaFunction::create_with`], not through [`LuaFunction::call`].
+    const STATEFUL: bool = false;
+    /// Invoke the function with the specified lua arguments,
+    /// converting the arguments and the result.
+    #[inline]
+    fn call<'lua>(lua: &'lua Lua, args: MultiValue<'lua>) -> mlua::Result<MultiValue<'lua>> {
+        Self::call_args(lua, FunctionArgs::new(lua, Self::NAME, args))
+    }
+    /// Invoke the function, converting the arguments with the specified helper.
+    ///
+    /// This is an implementation detail of [`LuaFunction::call`] and [`create_overloaded`].
+    #[doc(hidden)]
+    fn call_args<'lua>(lua: &'lua Lua, args: FunctionArgs<'lua>) -> mlua::Result<MultiValue<'lua>>;
+    /// Create a lua function object that invokes [`LuaFunction::call`],
+    /// using the default options.
+    #[inline]
+    fn create<'lua>(lua: &'lua Lua) -> mlua::Result<Function<'lua>> {
+        Self::create_with(lua, &FunctionOptions::default())
+    }
+    /// Create a lua function object that invokes [`LuaFunction::call`],
+    /// using the specified options.
+    fn create_with<'lua>(
+        lua: &'lua Lua,
+        options: &FunctionOptions,
+    ) -> mlua::Result<Function<'lua>> {
+        if Self::CATCH_PANIC.unwrap_or(options.catch_panics) {
+            let capture_backtrace = options.capture_backtrace;
+            lua.create_function(move |lua, args: MultiValue| {
+                crate::panic::catch_panic(Self::NAME, capture_backtrace, || Self::call(lua, args))
+            })
+        } else {
+            lua.create_function(Self::call)
+        }
+    }
+}
+
+/// One candidate for an overloaded function, see [`create_overloaded`].
+#[derive(Copy, Clone)]
+pub struct Overload {
+    name: &'static str,
+    signature: &'static str,
+    param_count: usize,
+    catch_panic: Option<bool>,
+    stateful: bool,
+    call: for<'lua> fn(&'lua Lua, MultiValue<'lua>) -> mlua::Result<MultiValue<'lua>>,
+}
+impl Overload {
+    /// The overload corresponding to the specified function
+    #[inline]
+    pub fn of<F: LuaFunction>() -> Self {
+        Overload {
+            name: F::NAME,
+            signature: F::SIGNATURE,
+            param_count: F::PARAM_COUNT,
+            catch_panic: F::CATCH_PANIC,
+            stateful: F::STATEFUL,
+            call: call_overload::<F>,
+        }
+    }
+}
+
+fn call_overload<'lua, F: LuaFunction>(
+    lua: &'lua Lua,
+    args: MultiValue<'lua>,
+) -> mlua::Result<MultiValue<'lua>> {
+    F::call_args(lua, FunctionArgs::for_overload(lua, F::NAME, args))
+}
+
+/// Create a single lua function that dispatches to one of several overloads,
+/// based on the types of its arguments.
+///
+/// Candidates are tried in order of specificity (most parameters first),
+/// falling back to the order they were given in.
+/// Numbers, strings and booleans only match values of exactly that lua type,
+/// so `"1"` is not accepted by a candidate expecting an `i64`.
+/// If no candidate accepts the arguments, the error lists every signature.
+pub fn create_overloaded<'lua>(
+    lua: &'lua Lua,
+    name: &'static str,
+    options: &FunctionOptions,
+    mut overloads: Vec<Overload>,
+) -> mlua::Result<Function<'lua>> {
+    if let Some(stateful) = overloads.iter().find(|overload| overload.stateful) {
+        return Err(mlua::Error::RuntimeError(format!(
+            "'{}' keeps state between calls, so it can't be used as an overload of '{name}'",
+            stateful.name
+        )));
+    }
+    overloads.sort_by_key(|overload| std::cmp::Reverse(overload.param_count));
+    let options = options.clone();
+    lua.create_function(move |lua, args: MultiValue| {
+        for overload in &overloads {
+            let call = || (overload.call)(lua, args.clone());
+            let result = if overload.catch_panic.unwrap_or(options.catch_panics) {
+                crate::panic::catch_panic(overload.name, options.capture_backtrace, call)
+            } else {
+                call()
+            };
+            match result {
+                Err(ref err) if parse_helpers::is_overload_mismatch(err) => continue,
+                result => return result,
+            }
+        }
+        let arg_types = args
+            .iter()
+            .map(|arg| parse_helpers::lua_value_type(arg.type_name()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut msg = format!(
+            "no overload of '{name}' matches the arguments ({arg_types}), expected one of:"
+        );
+        for overload in &overloads {
+            msg.push_str("\n    ");
+            msg.push_str(overload.signature);
+        }
+        Err(mlua::Error::RuntimeError(msg))
+    })
+}
+
+/// Options for creating a [`LuaFunction`].
+///
+/// These are usually specified for an entire module,
+/// although some can be overridden by individual functions.
+#[derive(Clone, Debug)]
+pub struct FunctionOptions {
+    /// Convert panics into lua errors, instead of unwinding through lua.
+    ///
+    /// This is enabled by default.
+    /// The location of the panic is only included in the error
+    /// after the application calls [`crate::panic::install_hook`].
+    pub catch_panics: bool,
+    /// Capture a backtrace when converting a panic into a lua error.
+    ///
+    /// This is disabled by default, because it is expensive.
+    /// It also requires calling [`crate::panic::install_hook`].
+    pub capture_backtrace: bool,
+    /// State shared by all the functions in a module,
+    /// which is accessed using `#[lua(module_state)]` parameters.
+    pub module_state: Option<ModuleState>,
+}
+impl Default for FunctionOptions {
+    #[inline]
+    fn default() -> Self {
+        FunctionOptions {
+            catch_panics: true,
+            capture_backtrace: false,
+            module_state: None,
+        }
+    }
+}
+
+/// State shared by all the functions in a module.
+///
+/// This is declared using `state => ...` in `declare_simple_module!`,
+/// and can be of any type.
+#[derive(Clone)]
+pub struct ModuleState {
+    value: Rc<dyn Any>,
+    type_name: &'static str,
+}
+impl ModuleState {
+    /// Wrap the specified value, so it can be shared between functions
+    pub fn new<T: 'static>(value: T) -> Self {
+        ModuleState {
+            value: Rc::new(RefCell::new(value)),
+            type_name: std::any::type_name::<T>(),
+        }
+    }
+}
+impl Debug for ModuleState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModuleState")
+            .field("type_name", &self.type_name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Get the module state needed by the specified function,
+/// failing if it is missing or has the wrong type.
+pub fn module_state<T: 'static>(
+    func_name: &str,
+    options: &FunctionOptions,
+) -> mlua::Result<Rc<RefCell<T>>> {
+    let state = options.module_state.as_ref().ok_or_else(|| {
+        mlua::Error::RuntimeError(format!(
+            "'{func_name}' requires module state, but none was declared (use `state => ...`)"
+        ))
+    })?;
+    Rc::clone(&state.value)
+        .downcast::<RefCell<T>>()
+        .map_err(|_| {
+            mlua::Error::RuntimeError(format!(
+                "'{func_name}' requires module state of type {}, but the module has {}",
+                std::any::type_name::<T>(),
+                state.type_name
+            ))
+        })
+}
+
+/// The error returned by [`LuaFunction::call`] for stateful functions
+pub fn stateful_call_error(func_name: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(format!(
+        "'{func_name}' uses state that only exists in the lua function created by \
+        `LuaFunction::create_with`, so it can't be invoked with `LuaFunction::call`"
+    ))
+}
+
+/// Convert an arbitrary rust error into a lua error.
+///
+/// This is used by `#[lua_function]` for functions that return `Result<T, E>`.
+/// Errors that are already an [`mlua::Error`] are preserved as-is,
+/// everything else becomes an [`mlua::Error::ExternalError`].
+pub fn into_lua_error<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> mlua::Error {
+    match err.into().downcast::<mlua::Error>() {
+        Ok(lua_error) => *lua_error,
+        Err(other) => mlua::Error::ExternalError(other.into()),
+    }
+}
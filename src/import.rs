@@ -0,0 +1,140 @@
+//! Calling lua functions from rust, as generated by `#[lua_import]` and `#[lua_trait]`.
+//!
+//! These helpers look up the lua function and convert the arguments and results,
+//! producing errors that name the lua function if anything goes wrong.
+use mlua::{FromLuaMulti, Function, Lua, Table, ToLuaMulti, Value};
+
+use crate::parse_helpers::{conversion_detail, lua_value_type};
+
+/// Call the lua function stored in the specified global
+pub fn call_global<'lua, A, R, E>(lua: &'lua Lua, name: &str, args: A) -> Result<R, E>
+where
+    A: ToLuaMulti<'lua>,
+    R: FromLuaMulti<'lua>,
+    E: From<mlua::Error>,
+{
+    let value: Value<'lua> = lua.globals().get(name)?;
+    let func = expect_function(name, value)?;
+    call_function(name, &func, args).map_err(E::from)
+}
+
+/// Call the lua function at the specified dotted path (like `game.hooks.on_tick`),
+/// starting from the globals.
+pub fn call_path<'lua, A, R, E>(lua: &'lua Lua, path: &str, args: A) -> Result<R, E>
+where
+    A: ToLuaMulti<'lua>,
+    R: FromLuaMulti<'lua>,
+    E: From<mlua::Error>,
+{
+    let mut value = Value::Table(lua.globals());
+    let mut traversed = 0;
+    for part in path.split('.') {
+        let table = match value {
+            Value::Table(table) => table,
+            other => {
+                return Err(E::from(mlua::Error::RuntimeError(format!(
+                    "can't find lua function '{path}' ('{}' is {}, not a table)",
+                    &path[..traversed - 1],
+                    other.type_name(),
+                ))))
+            }
+        };
+        value = table.get(part)?;
+        traversed += part.len() + 1;
+    }
+    let func = expect_function(path, value)?;
+    call_function(path, &func, args).map_err(E::from)
+}
+
+/// Call the lua function stored in the specified field of a table
+pub fn call_field<'lua, A, R, E>(table: &Table<'lua>, name: &str, args: A) -> Result<R, E>
+where
+    A: ToLuaMulti<'lua>,
+    R: FromLuaMulti<'lua>,
+    E: From<mlua::Error>,
+{
+    let value: Value<'lua> = table.get(name)?;
+    let func = expect_function(name, value)?;
+    call_function(name, &func, args).map_err(E::from)
+}
+
+/// A lua object, which implements rust traits marked with `#[lua_trait]`
+/// by calling the corresponding lua methods (`obj:method(args)`).
+///
+/// Usually the object is a [`Table`], which may use metatables for inheritance.
+#[derive(Clone, Debug)]
+pub struct LuaImpl<T> {
+    object: T,
+}
+impl<T> LuaImpl<T> {
+    /// Wrap the specified lua object
+    #[inline]
+    pub fn new(object: T) -> Self {
+        LuaImpl { object }
+    }
+    /// The underlying lua object
+    #[inline]
+    pub fn object(&self) -> &T {
+        &self.object
+    }
+    /// Convert into the underlying lua object
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.object
+    }
+}
+
+/// Look up a method of a lua object, returning `None` if it isn't defined
+pub fn find_method<'lua>(object: &Table<'lua>, name: &str) -> mlua::Result<Option<Function<'lua>>> {
+    match object.get::<_, Value<'lua>>(name)? {
+        Value::Nil => Ok(None),
+        value => expect_function(name, value).map(Some),
+    }
+}
+
+/// Call a method of a lua object, passing the object as the first argument
+pub fn call_method<'lua, A, R, E>(
+    object: &Table<'lua>,
+    func: &Function<'lua>,
+    name: &str,
+    args: A,
+) -> Result<R, E>
+where
+    A: ToLuaMulti<'lua>,
+    R: FromLuaMulti<'lua>,
+    E: From<mlua::Error>,
+{
+    call_function(name, func, (object.clone(), args)).map_err(E::from)
+}
+
+/// The error for a required method the lua object doesn't define
+pub fn missing_method(name: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("lua object doesn't define the method '{name}'"))
+}
+
+fn expect_function<'lua>(name: &str, value: Value<'lua>) -> mlua::Result<Function<'lua>> {
+    match value {
+        Value::Function(func) => Ok(func),
+        Value::Nil => Err(mlua::Error::RuntimeError(format!(
+            "lua function '{name}' is not defined"
+        ))),
+        other => Err(mlua::Error::RuntimeError(format!(
+            "lua function '{name}' should be a function, not {}",
+            lua_value_type(other.type_name())
+        ))),
+    }
+}
+
+fn call_function<'lua, A, R>(name: &str, func: &Function<'lua>, args: A) -> mlua::Result<R>
+where
+    A: ToLuaMulti<'lua>,
+    R: FromLuaMulti<'lua>,
+{
+    func.call(args).map_err(|err| match err {
+        mlua::Error::FromLuaConversionError { .. } => mlua::Error::RuntimeError(format!(
+            "bad return value from lua function '{name}' ({})",
+            conversion_detail(&err)
+        )),
+        other => other,
+    })
+}
@@ -0,0 +1,60 @@
+//! Exposing rust iterators to lua
+
+use std::iter::Fuse;
+
+use mlua::{Lua, MultiValue, ToLua, ToLuaMulti, Value};
+
+/// A rust iterator, which is converted into a lua iterator function.
+///
+/// This works with the generic `for` loop (`for x in iter do ... end`),
+/// converting each item with [`ToLuaMulti`] so that tuples become multiple loop variables.
+/// Once the iterator is exhausted, the function keeps returning `nil`.
+///
+/// Functions marked with `#[lua_function]` that return `impl Iterator`
+/// are wrapped automatically.
+/// Panics in the iterator are handled the same way as in the function that returned it
+/// (see [`catch_panic`](crate::panic::catch_panic)), so they are only caught
+/// if the iterator is converted while catching panics.
+#[derive(Clone, Debug)]
+pub struct LuaIter<I> {
+    iter: Fuse<I>,
+}
+impl<I: Iterator> LuaIter<I> {
+    /// Wrap the specified iterator
+    #[inline]
+    pub fn new<T: IntoIterator<IntoIter = I>>(iter: T) -> Self {
+        LuaIter {
+            iter: iter.into_iter().fuse(),
+        }
+    }
+}
+impl<I: Iterator> Iterator for LuaIter<I> {
+    type Item = I::Item;
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        self.iter.next()
+    }
+}
+impl<'lua, I> ToLua<'lua> for LuaIter<I>
+where
+    I: Iterator + 'static,
+    I::Item: for<'a> ToLuaMulti<'a>,
+{
+    fn to_lua(mut self, lua: &'lua Lua) -> mlua::Result<Value<'lua>> {
+        let catch = crate::panic::current_catch();
+        // NOTE: The generic for passes the state and control variables, which we ignore
+        let func = lua.create_function_mut(move |lua, ()| {
+            let mut next = || match self.next() {
+                Some(item) => item.to_lua_multi(lua),
+                None => Ok(MultiValue::from_vec(vec![Value::Nil])),
+            };
+            match catch {
+                Some((function, capture_backtrace)) => {
+                    crate::panic::catch_panic(function, capture_backtrace, next)
+                }
+                None => next(),
+            }
+        })?;
+        Ok(Value::Function(func))
+    }
+}
@@ -1,8 +1,27 @@
 #![doc = include_str!("../README.md")]
 #![deny(missing_docs)]
 
+// Bytecode is only valid for the vendored lua it was dumped by,
+// which module mode doesn't link (the host application provides lua instead)
+#[cfg(all(feature = "precompile-lua", feature = "module"))]
+compile_error!("The `precompile-lua` feature can't be used with `module`");
+
+pub mod callback;
+pub mod enums;
+pub mod func;
+pub mod import;
+pub mod iter;
+pub mod module;
+pub mod panic;
 pub mod parse_helpers;
 pub mod prelude;
 pub mod tostring;
+pub mod userdata;
 
+pub use callback::Callback;
+pub use enums::LuaEnum;
+pub use func::{FunctionOptions, LuaFunction};
+pub use import::LuaImpl;
+pub use iter::LuaIter;
 pub use luao3_macros::*;
+pub use module::LuaModule;
@@ -0,0 +1,362 @@
+//! Installing modules declared with `declare_simple_module!`, so lua can `require` them.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use mlua::{ChunkMode, Function, Lua, MultiValue, Table, Value};
+
+/// A module that can be loaded by lua.
+///
+/// The `declare_simple_module!` macro leaves the generated function untouched,
+/// and implements this trait for a hidden marker type with the same name
+/// (the same trick used by `#[lua_function]`).
+pub trait LuaModule: 'static {
+    /// The name used to `require` the module
+    const NAME: &'static str;
+    /// The documentation of the module, given by `doc => "..."`
+    const DOC: Option<&'static str> = None;
+    /// The members of the module, in the order they were declared
+    const MEMBERS: &'static [ModuleMember] = &[];
+    /// Create a fresh instance of the module
+    fn create(lua: &Lua) -> mlua::Result<Table<'_>>;
+    /// Register the module in `package.preload`,
+    /// so that it is created the first time a script calls `require`.
+    fn register_preload(lua: &Lua) -> mlua::Result<()> {
+        register_loader(lua, Self::NAME, Self::create)
+    }
+    /// Load the module immediately (as if by `require`),
+    /// then also expose it as a global with the same name.
+    ///
+    /// If the module was already required, the existing instance is reused.
+    fn install_global(lua: &Lua) -> mlua::Result<Table<'_>> {
+        let loaded = package_table(lua, "loaded")?;
+        let module = match loaded.get::<_, Option<Table>>(Self::NAME)? {
+            Some(existing) => existing,
+            None => {
+                Self::register_preload(lua)?;
+                let require: Function = lua.globals().get("require")?;
+                require.call(Self::NAME)?
+            }
+        };
+        lua.globals().set(Self::NAME, module.clone())?;
+        Ok(module)
+    }
+}
+
+/// Metadata describing a single member of a [`LuaModule`]
+#[derive(Copy, Clone, Debug)]
+pub struct ModuleMember {
+    /// The name of the member in lua
+    pub name: &'static str,
+    /// What kind of member this is
+    pub kind: MemberKind,
+    /// The doc comments on the member, if any
+    pub doc: Option<&'static str>,
+}
+
+/// The different kinds of [`ModuleMember`],
+/// corresponding to the different declarations in `declare_simple_module!`
+#[derive(Copy, Clone, Debug)]
+#[non_exhaustive]
+pub enum MemberKind {
+    /// A function declared with `fn foo`
+    Function {
+        /// The signature of the function, see [`crate::LuaFunction::SIGNATURE`]
+        signature: &'static str,
+    },
+    /// Several functions declared with `overload foo { ... }`
+    Overload {
+        /// The signatures of each candidate
+        signatures: &'static [&'static str],
+    },
+    /// A value declared with `const FOO = ...` or `value foo = ...`
+    Value,
+    /// A userdata type declared with `class Foo`
+    Class {
+        /// The name of the type, see [`crate::userdata::LuaMethods::CLASS_NAME`]
+        class_name: &'static str,
+    },
+    /// An enum declared with `enum Foo`
+    Enum {
+        /// The names of the variants
+        variants: &'static [&'static str],
+    },
+    /// A nested table declared with `mod foo { ... }`
+    Submodule {
+        /// The members of the nested table
+        members: &'static [ModuleMember],
+    },
+    /// Another module, declared with `module foo`
+    Module {
+        /// The name of the other module
+        name: &'static str,
+        /// The members of the other module
+        members: &'static [ModuleMember],
+    },
+}
+
+/// A module registered in the crate-wide registry.
+///
+/// With the `registry` feature, every module declared with `declare_simple_module!`
+/// registers itself at link time, so the host application can enumerate them
+/// using [`registered_modules`].
+#[cfg(feature = "registry")]
+#[derive(Copy, Clone, Debug)]
+pub struct RegisteredModule {
+    /// The name of the module
+    pub name: &'static str,
+    /// The documentation of the module
+    pub doc: Option<&'static str>,
+    /// The members of the module
+    pub members: &'static [ModuleMember],
+    create: for<'lua> fn(&'lua Lua) -> mlua::Result<Table<'lua>>,
+}
+#[cfg(feature = "registry")]
+impl RegisteredModule {
+    /// The registry entry for the specified module
+    pub const fn of<M: LuaModule>() -> Self {
+        RegisteredModule {
+            name: M::NAME,
+            doc: M::DOC,
+            members: M::MEMBERS,
+            create: M::create,
+        }
+    }
+    /// Create a fresh instance of the module, see [`LuaModule::create`]
+    #[inline]
+    pub fn create<'lua>(&self, lua: &'lua Lua) -> mlua::Result<Table<'lua>> {
+        (self.create)(lua)
+    }
+    /// Register the module in `package.preload`, see [`LuaModule::register_preload`]
+    #[inline]
+    pub fn register_preload(&self, lua: &Lua) -> mlua::Result<()> {
+        register_loader(lua, self.name, self.create)
+    }
+}
+#[cfg(feature = "registry")]
+inventory::collect!(RegisteredModule);
+
+/// Iterate over every module in the crate-wide registry,
+/// in an unspecified order.
+#[cfg(feature = "registry")]
+pub fn registered_modules() -> impl Iterator<Item = &'static RegisteredModule> {
+    inventory::iter::<RegisteredModule>.into_iter()
+}
+
+/// Find the module with the specified name in the crate-wide registry
+#[cfg(feature = "registry")]
+pub fn find_registered_module(name: &str) -> Option<&'static RegisteredModule> {
+    registered_modules().find(|module| module.name == name)
+}
+
+#[cfg(feature = "registry")]
+#[doc(hidden)]
+pub use inventory as __inventory;
+
+/// Add a module to the crate-wide registry, if the `registry` feature is enabled.
+///
+/// This is invoked by `declare_simple_module!`.
+#[cfg(feature = "registry")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __register_module {
+    ($module:ty) => {
+        $crate::module::__inventory::submit! {
+            $crate::module::RegisteredModule::of::<$module>()
+        }
+    };
+}
+/// Add a module to the crate-wide registry, if the `registry` feature is enabled.
+///
+/// This is invoked by `declare_simple_module!`.
+#[cfg(not(feature = "registry"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __register_module {
+    ($module:ty) => {};
+}
+
+/// Lua code embedded in a module with a `lua "file.lua"` member
+///
+/// The syntax of the code is checked when the module is compiled.
+#[derive(Copy, Clone, Debug)]
+pub enum EmbeddedChunk {
+    /// The source code of the chunk, included as-is
+    Source(&'static str),
+    /// The chunk precompiled into bytecode, with the `precompile-lua` feature
+    ///
+    /// The bytecode comes from the vendored lua used by the macros at compile time,
+    /// so it is only valid for the same vendored lua (and architecture),
+    /// which is why `precompile-lua` requires the `vendored` feature.
+    /// Binary chunks are also disabled by mlua in safe mode (see [`Lua::unsafe_new`]).
+    /// Whenever the bytecode can't be loaded, the source is used as a fallback.
+    Precompiled {
+        /// The bytecode produced by `string.dump`
+        bytecode: &'static [u8],
+        /// The original source code
+        source: &'static str,
+    },
+}
+
+/// Creates the environment for an embedded chunk,
+/// where the members of the module shadow the globals.
+const CHUNK_ENVIRONMENT: &str = r#"
+local members, globals = ...
+return setmetatable({}, {
+    __index = function(_, key)
+        local value = members[key]
+        if value == nil then
+            value = globals[key]
+        end
+        return value
+    end,
+})
+"#;
+
+/// Run a chunk embedded in the specified module,
+/// merging the table it returns (if any) into the module.
+///
+/// The chunk can access the existing members of the module as if they were globals,
+/// and also receives the module itself as an argument.
+/// This is invoked by `declare_simple_module!` for `lua "file.lua"` members.
+pub fn run_embedded_chunk<'lua>(
+    lua: &'lua Lua,
+    module: &Table<'lua>,
+    path: &str,
+    chunk: EmbeddedChunk,
+) -> mlua::Result<()> {
+    let env: Table = lua
+        .load(CHUNK_ENVIRONMENT)
+        .set_name("=luao3 chunk environment")?
+        .call((module.clone(), lua.globals()))?;
+    let name = format!("@{path}");
+    let load = |code: &'static [u8], mode: ChunkMode| {
+        lua.load(code)
+            .set_mode(mode)
+            .set_name(&name)?
+            .set_environment(env.clone())?
+            .into_function()
+    };
+    let func = match chunk {
+        EmbeddedChunk::Source(source) => load(source.as_bytes(), ChunkMode::Text)?,
+        EmbeddedChunk::Precompiled { bytecode, source } => {
+            match load(bytecode, ChunkMode::Binary) {
+                Ok(func) => func,
+                Err(_) => load(source.as_bytes(), ChunkMode::Text)?,
+            }
+        }
+    };
+    let exports: Option<Table> = func.call(module.clone())?;
+    if let Some(exports) = exports {
+        for pair in exports.pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            if module.contains_key(key.clone())? {
+                let key = match key {
+                    Value::String(ref text) => text.to_string_lossy().into_owned(),
+                    ref other => other.type_name().into(),
+                };
+                return Err(mlua::Error::RuntimeError(format!(
+                    "'{path}' redefines the existing module member '{key}'"
+                )));
+            }
+            module.set(key, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// A member of a lazy module, which is only created on first access.
+///
+/// This is used by `declare_simple_module!` with `lazy => true`.
+pub struct LazyMember {
+    name: &'static str,
+    create: Box<CreateMember>,
+}
+type CreateMember = dyn for<'lua> Fn(&'lua Lua) -> mlua::Result<Value<'lua>>;
+impl LazyMember {
+    /// A member with the specified name, created by the specified function
+    pub fn new<F>(name: &'static str, create: F) -> Self
+    where
+        F: for<'lua> Fn(&'lua Lua) -> mlua::Result<Value<'lua>> + 'static,
+    {
+        LazyMember {
+            name,
+            create: Box::new(create),
+        }
+    }
+}
+
+/// Create a module table whose members are only created when they are first accessed,
+/// then cached in the table.
+///
+/// Each member is created at most once, even if it turns out to be `nil`.
+///
+/// The `__pairs` metamethod creates all the remaining members before iterating,
+/// so `pairs(module)` still sees everything.
+/// Lua 5.1 and LuaJIT ignore `__pairs`, so there `pairs` only sees the members that were
+/// already accessed (`next` never sees the missing members in any version).
+pub fn lazy_table(lua: &Lua, members: Vec<LazyMember>) -> mlua::Result<Table<'_>> {
+    // NOTE: Members are removed once they are created, so `nil` values aren't recreated
+    let members: HashMap<&'static str, LazyMember> = members
+        .into_iter()
+        .map(|member| (member.name, member))
+        .collect();
+    let members = Rc::new(RefCell::new(members));
+    // Capture `next` now, in case the globals are changed later
+    let next = lua.create_registry_value(lua.globals().get::<_, Function>("next")?)?;
+    let metatable = lua.create_table()?;
+    let index_members = Rc::clone(&members);
+    metatable.set(
+        "__index",
+        lua.create_function(move |lua, (table, key): (Table, Value)| {
+            let name = match key {
+                Value::String(ref name) => name.to_str().ok(),
+                _ => None,
+            };
+            let member = name.and_then(|name| index_members.borrow_mut().remove(name));
+            match member {
+                Some(member) => {
+                    let value = (member.create)(lua)?;
+                    table.raw_set(key, value.clone())?;
+                    Ok(value)
+                }
+                None => Ok(Value::Nil),
+            }
+        })?,
+    )?;
+    metatable.set(
+        "__pairs",
+        lua.create_function(move |lua, table: Table| {
+            let remaining: Vec<&'static str> = members.borrow().keys().copied().collect();
+            for name in remaining {
+                // NOTE: This goes through __index for missing members
+                table.get::<_, Value>(name)?;
+            }
+            let next: Function = lua.registry_value(&next)?;
+            Ok((next, table, Value::Nil))
+        })?,
+    )?;
+    let table = lua.create_table()?;
+    table.set_metatable(Some(metatable));
+    Ok(table)
+}
+
+fn register_loader(
+    lua: &Lua,
+    name: &str,
+    create: for<'lua> fn(&'lua Lua) -> mlua::Result<Table<'lua>>,
+) -> mlua::Result<()> {
+    let loader = lua.create_function(move |lua, _: MultiValue| create(lua))?;
+    package_table(lua, "preload")?.set(name, loader)
+}
+
+fn package_table<'lua>(lua: &'lua Lua, field: &str) -> mlua::Result<Table<'lua>> {
+    let package: Option<Table> = lua.globals().get("package")?;
+    let package = package.ok_or_else(|| {
+        mlua::Error::RuntimeError(
+            "can't install lua module, because the package library isn't loaded".into(),
+        )
+    })?;
+    package.get(field)
+}
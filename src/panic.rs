@@ -0,0 +1,159 @@
+//! Converts rust panics into lua errors.
+//!
+//! By default, every `#[lua_function]` catches panics,
+//! so a bug in one binding doesn't tear down the entire host process.
+//!
+//! Recording the location and backtrace of a panic requires a panic hook,
+//! which is process-wide state that belongs to the application.
+//! So it is only installed by an explicit call to [`install_hook`].
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::fmt::{self, Display, Formatter};
+use std::panic::AssertUnwindSafe;
+use std::sync::Once;
+
+/// A rust panic that was caught before it could unwind through lua.
+///
+/// This is wrapped in an [`mlua::Error::ExternalError`],
+/// so the host application can downcast to it.
+#[derive(Debug)]
+pub struct RustPanic {
+    function: &'static str,
+    message: String,
+    location: Option<String>,
+    backtrace: Option<Backtrace>,
+}
+impl RustPanic {
+    /// The name of the function that panicked
+    #[inline]
+    pub fn function(&self) -> &'static str {
+        self.function
+    }
+    /// The panic message (if the payload was a string)
+    #[inline]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+    /// The location of the panic, if it could be determined
+    #[inline]
+    pub fn location(&self) -> Option<&str> {
+        self.location.as_deref()
+    }
+    /// The backtrace of the panic, if one was requested
+    #[inline]
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.backtrace.as_ref()
+    }
+}
+impl Display for RustPanic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Rust panic in '{}': {}", self.function, self.message)?;
+        if let Some(ref location) = self.location {
+            write!(f, " (at {location})")?;
+        }
+        if let Some(ref backtrace) = self.backtrace {
+            write!(f, "\nbacktrace:\n{backtrace}")?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for RustPanic {}
+
+/// Details about a panic, recorded by our panic hook
+struct CapturedPanic {
+    function: &'static str,
+    capture_backtrace: bool,
+    location: Option<String>,
+    backtrace: Option<Backtrace>,
+}
+
+thread_local! {
+    /// The innermost call to [`catch_panic`] on this thread (if any)
+    static CURRENT_CAPTURE: RefCell<Option<CapturedPanic>> = const { RefCell::new(None) };
+}
+
+/// Install a panic hook that records the location of panics caught by [`catch_panic`],
+/// along with a backtrace if [`FunctionOptions::capture_backtrace`] is enabled.
+///
+/// This replaces the process-wide hook (see [`std::panic::set_hook`]),
+/// so it is never done automatically.
+/// The new hook chains to the previous one, so panics are still reported as usual.
+/// Calling this more than once has no effect.
+///
+/// [`FunctionOptions::capture_backtrace`]: crate::FunctionOptions::capture_backtrace
+pub fn install_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            // NOTE: Use try_with, because the thread-local may already be destroyed
+            let _ = CURRENT_CAPTURE.try_with(|current| {
+                if let Some(ref mut capture) = *current.borrow_mut() {
+                    capture.location = info.location().map(|loc| loc.to_string());
+                    if capture.capture_backtrace {
+                        capture.backtrace = Some(Backtrace::force_capture());
+                    }
+                }
+            });
+            previous(info)
+        }));
+    });
+}
+
+/// Run the specified function, converting any panics into a [`RustPanic`] error.
+///
+/// The `function` name is used in the resulting error message.
+/// The location and backtrace are only available after calling [`install_hook`].
+pub fn catch_panic<R>(
+    function: &'static str,
+    capture_backtrace: bool,
+    func: impl FnOnce() -> mlua::Result<R>,
+) -> mlua::Result<R> {
+    let outer = CURRENT_CAPTURE.with(|current| {
+        current.replace(Some(CapturedPanic {
+            function,
+            capture_backtrace,
+            location: None,
+            backtrace: None,
+        }))
+    });
+    let res = std::panic::catch_unwind(AssertUnwindSafe(func));
+    let captured = CURRENT_CAPTURE.with(|current| current.replace(outer));
+    match res {
+        Ok(res) => res,
+        Err(payload) => {
+            let captured = captured.expect("missing panic capture");
+            Err(mlua::Error::external(RustPanic {
+                function,
+                message: panic_message(&*payload),
+                location: captured.location,
+                backtrace: captured.backtrace,
+            }))
+        }
+    }
+}
+
+/// The name and `capture_backtrace` setting of the innermost [`catch_panic`] on this thread,
+/// or `None` if panics aren't being caught.
+///
+/// This lets lua functions created during a call (like iterators)
+/// handle panics the same way as the function that created them.
+pub(crate) fn current_catch() -> Option<(&'static str, bool)> {
+    CURRENT_CAPTURE.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .map(|capture| (capture.function, capture.capture_backtrace))
+    })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&'static str>() {
+        (*msg).into()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "Box<dyn Any>".into()
+    }
+}
@@ -3,9 +3,13 @@
 //! These are not public and only intended for the macros.
 #![allow(missing_docs)]
 
+use std::cell::{Ref, RefCell, RefMut};
 use std::fmt::Display;
+use std::ops::{Deref, DerefMut};
 
-use mlua::{FromLua, Lua, Value};
+use crate::callback::{Callback, CallbackOrigin};
+
+use mlua::{AnyUserData, FromLua, FromLuaMulti, Lua, MultiValue, UserData, Value, Variadic};
 
 pub fn expect_table<'lua>(
     value: mlua::Value<'lua>,
@@ -70,6 +74,16 @@ pub fn parse_enum_externally_tagged<'lua>(
         })
     }
 }
+/// Parse a callback field, remembering where it came from for error messages
+pub fn parse_callback_field<'lua, Args, Ret>(
+    lua: &'lua mlua::Lua,
+    target_type: &'static str,
+    lua_table: &mlua::Table<'lua>,
+    key: TableKey,
+) -> mlua::Result<Callback<'lua, Args, Ret>> {
+    let callback: Callback<'lua, Args, Ret> = parse_field(lua, target_type, lua_table, key)?;
+    Ok(callback.with_origin(CallbackOrigin::Field { target_type, key }))
+}
 pub fn parse_field<'lua, T: FromLua<'lua>>(
     lua: &'lua mlua::Lua,
     target_type: &'static str,
@@ -84,3 +98,422 @@ pub fn parse_field<'lua, T: FromLua<'lua>>(
         message: Some(format!("field {key}: {cause}")),
     })
 }
+
+/// The arguments passed to a `#[lua_function]`,
+/// converted one at a time so that errors can name the offending argument.
+pub struct FunctionArgs<'lua> {
+    lua: &'lua Lua,
+    func_name: &'static str,
+    values: <MultiValue<'lua> as IntoIterator>::IntoIter,
+    index: usize,
+    overload: bool,
+}
+impl<'lua> FunctionArgs<'lua> {
+    #[inline]
+    pub fn new(lua: &'lua Lua, func_name: &'static str, args: MultiValue<'lua>) -> Self {
+        FunctionArgs {
+            lua,
+            func_name,
+            values: args.into_iter(),
+            index: 0,
+            overload: false,
+        }
+    }
+    /// Convert the arguments for one candidate of an overloaded function.
+    ///
+    /// Arguments are checked strictly (without coercing strings to numbers),
+    /// and any mismatch gives an error that [`is_overload_mismatch`] recognizes,
+    /// so the next candidate can be tried instead.
+    #[inline]
+    pub fn for_overload(lua: &'lua Lua, func_name: &'static str, args: MultiValue<'lua>) -> Self {
+        FunctionArgs {
+            overload: true,
+            ..FunctionArgs::new(lua, func_name, args)
+        }
+    }
+    /// Convert the next argument, treating missing arguments as `nil` (just like lua)
+    pub fn next_arg<T: FromLua<'lua>>(&mut self) -> mlua::Result<T> {
+        self.index += 1;
+        let value = self.values.next().unwrap_or(Value::Nil);
+        T::from_lua(value, self.lua).map_err(|cause| self.bad_argument(cause))
+    }
+    /// Convert the next argument, which is expected to have the specified lua type.
+    ///
+    /// The type only matters when choosing between overloads.
+    pub fn next_arg_of<T: FromLua<'lua>>(
+        &mut self,
+        expected: LuaType,
+        optional: bool,
+    ) -> mlua::Result<T> {
+        self.index += 1;
+        let value = self.values.next().unwrap_or(Value::Nil);
+        self.convert_value(value, expected, optional)
+    }
+    /// Convert all the remaining arguments at once (for `MultiValue` and friends).
+    ///
+    /// When choosing between overloads, more than `limit` values don't match.
+    pub fn remaining_args<T: FromLuaMulti<'lua>>(
+        mut self,
+        limit: Option<usize>,
+    ) -> mlua::Result<T> {
+        if self.overload && matches!(limit, Some(limit) if self.values.len() > limit) {
+            return Err(overload_mismatch());
+        }
+        self.index += 1;
+        let remaining = MultiValue::from_iter(&mut self.values);
+        T::from_lua_multi(remaining, self.lua).map_err(|cause| self.bad_argument(cause))
+    }
+    /// Convert the remaining arguments one at a time,
+    /// so errors name the actual position of the bad value
+    pub fn remaining_variadic<T: FromLua<'lua>>(
+        mut self,
+        expected: Option<LuaType>,
+    ) -> mlua::Result<Variadic<T>> {
+        let mut res = Variadic::new();
+        while let Some(value) = self.values.next() {
+            self.index += 1;
+            res.push(match expected {
+                Some(expected) => self.convert_value(value, expected, false)?,
+                None => T::from_lua(value, self.lua).map_err(|cause| self.bad_argument(cause))?,
+            });
+        }
+        Ok(res)
+    }
+    /// Take the next argument as a userdata of type `T`, so it can be borrowed
+    pub fn next_userdata<T: UserData + 'static>(&mut self) -> mlua::Result<UserDataArg<'lua>> {
+        self.index += 1;
+        match self.values.next().unwrap_or(Value::Nil) {
+            Value::UserData(value) if value.is::<T>() => Ok(UserDataArg {
+                value,
+                func_name: self.func_name,
+                index: self.index,
+            }),
+            _ if self.overload => Err(overload_mismatch()),
+            other => Err(argument_error(
+                self.func_name,
+                self.index,
+                format!(
+                    "{} expected, got {}",
+                    short_type_name::<T>(),
+                    other.type_name()
+                ),
+            )),
+        }
+    }
+    /// Convert the next argument as a callback, remembering its position for error messages
+    pub fn next_callback<Args, Ret>(&mut self) -> mlua::Result<Callback<'lua, Args, Ret>> {
+        let callback: Callback<'lua, Args, Ret> = self.next_arg()?;
+        Ok(callback.with_origin(CallbackOrigin::Argument {
+            func_name: self.func_name,
+            index: self.index,
+        }))
+    }
+    /// Check there are no extra arguments left over.
+    ///
+    /// This only matters when choosing between overloads,
+    /// otherwise extra arguments are ignored (just like lua).
+    pub fn finish(&self) -> mlua::Result<()> {
+        if self.overload && self.values.len() > 0 {
+            Err(overload_mismatch())
+        } else {
+            Ok(())
+        }
+    }
+    fn convert_value<T: FromLua<'lua>>(
+        &self,
+        value: Value<'lua>,
+        expected: LuaType,
+        optional: bool,
+    ) -> mlua::Result<T> {
+        if self.overload && !(expected.matches(&value) || optional && matches!(value, Value::Nil)) {
+            return Err(overload_mismatch());
+        }
+        T::from_lua(value, self.lua).map_err(|cause| self.bad_argument(cause))
+    }
+    fn bad_argument(&self, cause: mlua::Error) -> mlua::Error {
+        if self.overload {
+            overload_mismatch()
+        } else {
+            bad_argument(self.func_name, self.index, &cause)
+        }
+    }
+}
+
+/// The lua type expected for a primitive argument,
+/// used to choose between overloads without coercing values.
+#[derive(Copy, Clone, Debug)]
+pub enum LuaType {
+    Boolean,
+    Number,
+    String,
+}
+impl LuaType {
+    fn matches(self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (LuaType::Boolean, Value::Boolean(_))
+                | (LuaType::Number, Value::Integer(_) | Value::Number(_))
+                | (LuaType::String, Value::String(_))
+        )
+    }
+}
+
+/// The arguments don't match one candidate of an overloaded function
+#[derive(Debug)]
+struct OverloadMismatch;
+impl Display for OverloadMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the arguments don't match the overload")
+    }
+}
+impl std::error::Error for OverloadMismatch {}
+
+fn overload_mismatch() -> mlua::Error {
+    mlua::Error::external(OverloadMismatch)
+}
+
+/// Check if the error came from [`FunctionArgs::for_overload`] rejecting the arguments
+pub fn is_overload_mismatch(err: &mlua::Error) -> bool {
+    matches!(*err, mlua::Error::ExternalError(ref cause) if cause.is::<OverloadMismatch>())
+}
+
+/// A userdata argument, which is borrowed for the duration of the call
+pub struct UserDataArg<'lua> {
+    value: AnyUserData<'lua>,
+    func_name: &'static str,
+    index: usize,
+}
+impl<'lua> UserDataArg<'lua> {
+    pub fn borrow<T: UserData + 'static>(&self) -> mlua::Result<Ref<'_, T>> {
+        self.value
+            .borrow::<T>()
+            .map_err(|cause| self.borrow_error::<T>(cause))
+    }
+    pub fn borrow_mut<T: UserData + 'static>(&self) -> mlua::Result<RefMut<'_, T>> {
+        self.value
+            .borrow_mut::<T>()
+            .map_err(|cause| self.borrow_error::<T>(cause))
+    }
+    fn borrow_error<T>(&self, cause: mlua::Error) -> mlua::Error {
+        let name = short_type_name::<T>();
+        let detail = match cause {
+            mlua::Error::UserDataBorrowError => format!("{name} already mutably borrowed"),
+            mlua::Error::UserDataBorrowMutError => format!("{name} already borrowed"),
+            mlua::Error::UserDataDestructed => format!("{name} has been destructed"),
+            other => other.to_string(),
+        };
+        argument_error(self.func_name, self.index, detail)
+    }
+}
+
+/// Fetch the app data needed by a `#[lua(app_data)] &T` parameter
+pub fn app_data_ref<'lua, T: 'static>(
+    lua: &'lua Lua,
+    func_name: &str,
+) -> mlua::Result<AppDataRef<'lua, T>> {
+    let borrow = AppDataBorrow::acquire::<T>(lua, func_name, false)?;
+    let value = lua
+        .app_data_ref::<T>()
+        .ok_or_else(|| missing_app_data::<T>(func_name))?;
+    Ok(AppDataRef {
+        value,
+        _borrow: borrow,
+    })
+}
+
+/// Fetch the app data needed by a `#[lua(app_data)] &mut T` parameter
+pub fn app_data_mut<'lua, T: 'static>(
+    lua: &'lua Lua,
+    func_name: &str,
+) -> mlua::Result<AppDataMut<'lua, T>> {
+    let borrow = AppDataBorrow::acquire::<T>(lua, func_name, true)?;
+    let value = lua
+        .app_data_mut::<T>()
+        .ok_or_else(|| missing_app_data::<T>(func_name))?;
+    Ok(AppDataMut {
+        value,
+        _borrow: borrow,
+    })
+}
+
+/// App data borrowed by a `#[lua(app_data)] &T` parameter
+pub struct AppDataRef<'lua, T> {
+    value: Ref<'lua, T>,
+    _borrow: AppDataBorrow<'lua>,
+}
+impl<T> Deref for AppDataRef<'_, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+/// App data mutably borrowed by a `#[lua(app_data)] &mut T` parameter
+pub struct AppDataMut<'lua, T> {
+    value: RefMut<'lua, T>,
+    _borrow: AppDataBorrow<'lua>,
+}
+impl<T> Deref for AppDataMut<'_, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+impl<T> DerefMut for AppDataMut<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+/// The registry key tracking the borrows made by `#[lua(app_data)]` parameters,
+/// which is a count of shared borrows (or `-1` when mutably borrowed).
+const APP_DATA_BORROWS: &str = "luao3.app_data_borrows";
+
+/// Tracks a borrow of the app data container, released when dropped.
+///
+/// mlua panics on conflicting borrows of the container (which is shared by all types),
+/// so the borrows are checked here first, producing an error instead.
+/// Borrows made directly through [`Lua::app_data_ref`] are not tracked.
+struct AppDataBorrow<'lua> {
+    lua: &'lua Lua,
+    exclusive: bool,
+}
+impl<'lua> AppDataBorrow<'lua> {
+    fn acquire<T>(lua: &'lua Lua, func_name: &str, exclusive: bool) -> mlua::Result<Self> {
+        let count = app_data_borrows(lua)?;
+        let conflict = match (exclusive, count) {
+            (_, count) if count < 0 => Some("mutably borrowed"),
+            (true, count) if count > 0 => Some("borrowed"),
+            _ => None,
+        };
+        if let Some(conflict) = conflict {
+            return Err(mlua::Error::RuntimeError(format!(
+                "'{func_name}' can't use the app data of type {}, because it is already {conflict}",
+                short_type_name::<T>()
+            )));
+        }
+        let updated = if exclusive { -1 } else { count + 1 };
+        lua.set_named_registry_value(APP_DATA_BORROWS, updated)?;
+        Ok(AppDataBorrow { lua, exclusive })
+    }
+}
+impl Drop for AppDataBorrow<'_> {
+    fn drop(&mut self) {
+        let updated = if self.exclusive {
+            0
+        } else {
+            app_data_borrows(self.lua).unwrap_or(1) - 1
+        };
+        // NOTE: This can only fail when lua is out of memory
+        let _ = self.lua.set_named_registry_value(APP_DATA_BORROWS, updated);
+    }
+}
+fn app_data_borrows(lua: &Lua) -> mlua::Result<i64> {
+    Ok(lua
+        .named_registry_value::<_, Option<i64>>(APP_DATA_BORROWS)?
+        .unwrap_or(0))
+}
+
+fn missing_app_data<T>(func_name: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(format!(
+        "'{func_name}' requires app data of type {}, which was never installed (see Lua::set_app_data)",
+        short_type_name::<T>()
+    ))
+}
+
+/// Borrow the module state needed by a `#[lua(module_state)] &T` parameter
+pub fn borrow_module_state<'a, T>(
+    state: &'a RefCell<T>,
+    func_name: &str,
+) -> mlua::Result<Ref<'a, T>> {
+    state.try_borrow().map_err(|_| {
+        mlua::Error::RuntimeError(format!(
+            "'{func_name}' can't use the module state, because it is already mutably borrowed"
+        ))
+    })
+}
+
+/// Borrow the module state needed by a `#[lua(module_state)] &mut T` parameter
+pub fn borrow_module_state_mut<'a, T>(
+    state: &'a RefCell<T>,
+    func_name: &str,
+) -> mlua::Result<RefMut<'a, T>> {
+    state.try_borrow_mut().map_err(|_| {
+        mlua::Error::RuntimeError(format!(
+            "'{func_name}' can't use the module state, because it is already borrowed"
+        ))
+    })
+}
+
+/// The name of a rust type, without any module path or generic arguments
+fn short_type_name<T>() -> &'static str {
+    let full = std::any::type_name::<T>();
+    let without_generics = full.split('<').next().unwrap_or(full);
+    without_generics
+        .rsplit("::")
+        .next()
+        .unwrap_or(without_generics)
+}
+
+/// Create an error in the style of `luaL_argerror`,
+/// like "bad argument #2 to 'resize' (number expected, got string)"
+pub fn bad_argument(func_name: &str, index: usize, cause: &mlua::Error) -> mlua::Error {
+    argument_error(func_name, index, conversion_detail(cause))
+}
+
+fn argument_error(func_name: &str, index: usize, detail: String) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("bad argument #{index} to '{func_name}' ({detail})"))
+}
+
+/// Describe a conversion error using lua type names,
+/// like "number expected, got string"
+pub fn conversion_detail(cause: &mlua::Error) -> String {
+    match *cause {
+        mlua::Error::FromLuaConversionError {
+            from,
+            to,
+            ref message,
+        } => {
+            let from = lua_value_type(from);
+            match (lua_type_name(to), message) {
+                // The type is right, but something else went wrong (like "out of range")
+                (Some(expected), Some(message)) if expected == from => message.clone(),
+                (Some(expected), _) => format!("{expected} expected, got {from}"),
+                (None, Some(message)) => format!("cannot convert {from} to {to}: {message}"),
+                (None, None) => format!("{to} expected, got {from}"),
+            }
+        }
+        ref other => other.to_string(),
+    }
+}
+
+/// The type name lua itself would give a value with the specified mlua type name.
+///
+/// Lua doesn't distinguish between integers and numbers in error messages,
+/// so this turns mlua's "integer" into "number".
+pub fn lua_value_type(type_name: &'static str) -> &'static str {
+    match type_name {
+        "integer" => "number",
+        other => other,
+    }
+}
+
+/// Translate the rust type names mlua uses in conversion errors
+/// into the lua type names users expect to see.
+fn lua_type_name(rust_name: &str) -> Option<&'static str> {
+    Some(match rust_name {
+        "i8" | "u8" | "i16" | "u16" | "i32" | "u32" | "i64" | "u64" | "i128" | "u128" | "isize"
+        | "usize" | "f32" | "f64" | "number" => "number",
+        "String" | "Box<str>" | "CString" | "BString" | "string" => "string",
+        "Array" | "Vec" | "Box<[T]>" | "HashMap" | "BTreeMap" | "HashSet" | "BTreeSet"
+        | "table" => "table",
+        "function" => "function",
+        "thread" => "thread",
+        "userdata" => "userdata",
+        "light userdata" => "lightuserdata",
+        _ => return None,
+    })
+}
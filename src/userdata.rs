@@ -0,0 +1,52 @@
+//! Support for exposing rust types to lua as userdata.
+use mlua::{Lua, MetaMethod, Table, UserDataFields};
+
+use crate::FunctionOptions;
+
+/// The methods of a type, as declared by `#[lua_methods]`.
+///
+/// By default, `#[lua_methods]` also implements [`mlua::UserData`] using these methods.
+/// Use `#[lua_methods(userdata = false)]` to implement it yourself.
+pub trait LuaMethods: mlua::UserData {
+    /// The name of the type, used as the `__name` of the metatable
+    /// and by `class Foo` members of `declare_simple_module!`.
+    const CLASS_NAME: &'static str;
+    /// Register all the methods declared with `#[lua_methods]`.
+    ///
+    /// Methods that take `&self` or `&mut self` can be invoked on instances,
+    /// while associated functions are registered as plain functions.
+    fn add_lua_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M);
+    /// Add the associated functions (like constructors) to the specified table.
+    ///
+    /// Unlike [`LuaMethods::add_lua_methods`], these can be called without an instance.
+    /// They also use the specified options to handle panics (like [`LuaFunction::create_with`]),
+    /// unless overridden by `#[lua_methods(catch_panic = ...)]`.
+    ///
+    /// [`LuaFunction::create_with`]: crate::LuaFunction::create_with
+    fn add_static_functions<'lua>(
+        lua: &'lua Lua,
+        table: &Table<'lua>,
+        options: &FunctionOptions,
+    ) -> mlua::Result<()>;
+}
+
+/// Register [`LuaMethods::CLASS_NAME`] as the `__name` of the metatable,
+/// which lua uses for `tostring` and in error messages.
+///
+/// This is done automatically unless you implement [`mlua::UserData`] yourself.
+pub fn add_class_name<'lua, T: LuaMethods, F: UserDataFields<'lua, T>>(fields: &mut F) {
+    fields.add_meta_field_with(MetaMethod::from(String::from("__name")), |_| {
+        Ok(T::CLASS_NAME)
+    });
+}
+
+/// Create a table with the associated functions of a type,
+/// like `module.Foo.new(...)`.
+pub fn class_table<'lua, T: LuaMethods>(
+    lua: &'lua Lua,
+    options: &FunctionOptions,
+) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    T::add_static_functions(lua, &table, options)?;
+    Ok(table)
+}
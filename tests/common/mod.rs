@@ -0,0 +1,17 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+
+use mlua::{Lua, Table};
+
+/// Create a lua state with a module installed as the specified global
+pub fn lua_with(name: &str, module: for<'lua> fn(&'lua Lua) -> mlua::Result<Table<'lua>>) -> Lua {
+    let lua = Lua::new();
+    let table = module(&lua).unwrap();
+    lua.globals().set(name, table).unwrap();
+    lua
+}
+
+/// Assert that the lua code evaluates to `true`
+pub fn check(lua: &Lua, code: &str) {
+    assert!(lua.load(code).eval::<bool>().unwrap(), "{code}");
+}
@@ -0,0 +1,18 @@
+//! Checks that invalid code is rejected when it is compiled
+use std::path::Path;
+
+#[test]
+fn compile_fail() {
+    // NOTE: trybuild compiles the cases as part of its own crate in the target directory,
+    // so the lua files they embed are resolved relative to that crate
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).parent().unwrap();
+    let lua_dir = target_dir.join("tests/trybuild/luao3/tests/lua");
+    std::fs::create_dir_all(&lua_dir).unwrap();
+    std::fs::copy(
+        "tests/lua/invalid_syntax.lua",
+        lua_dir.join("invalid_syntax.lua"),
+    )
+    .unwrap();
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/compile_fail/*.rs");
+}
@@ -0,0 +1,8 @@
+luao3::declare_simple_module! {
+    name => invalid,
+    members => {
+        lua "tests/lua/invalid_syntax.lua",
+    },
+}
+
+fn main() {}
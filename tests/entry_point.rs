@@ -0,0 +1,80 @@
+//! Loads the `entry_point` example (built with the `module` feature)
+//! from a standalone interpreter, the same way `require` works for any other lua module.
+//!
+//! This builds a standalone interpreter against the vendored Lua 5.4,
+//! so it is skipped if there's no C compiler.
+#![cfg(all(target_os = "linux", feature = "lua54"))]
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn run(cmd: &mut Command) -> String {
+    let output = cmd.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{cmd:?} failed with {}:\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// The profile directory of the nested build, which matches the profile of the tests
+const PROFILE: &str = if cfg!(debug_assertions) {
+    "debug"
+} else {
+    "release"
+};
+
+/// Find the vendored lua, which mlua builds (but doesn't link) in module mode
+fn find_vendored_lua(target_dir: &Path) -> PathBuf {
+    std::fs::read_dir(target_dir.join(PROFILE).join("build"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path().join("out"))
+        .find(|out| out.join("lib/liblua5.4.a").exists())
+        .expect("vendored lua should be built by mlua")
+}
+
+#[test]
+fn require_entry_point() {
+    let cc = std::env::var_os("CC").unwrap_or_else(|| OsString::from("cc"));
+    let cc_version = Command::new(&cc).arg("--version").output();
+    if !matches!(cc_version, Ok(ref output) if output.status.success()) {
+        eprintln!("skipping require_entry_point: no C compiler ({cc:?}) is available");
+        return;
+    }
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("entry_point");
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo"));
+    let mut build = Command::new(cargo);
+    build.args([
+        "build",
+        "--example",
+        "entry_point",
+        "--no-default-features",
+        "--features",
+        "lua54,module,vendored",
+    ]);
+    if PROFILE == "release" {
+        build.arg("--release");
+    }
+    run(build
+        .arg("--manifest-path")
+        .arg(manifest_dir.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target_dir));
+    let lua_dir = find_vendored_lua(&target_dir);
+    let interpreter = target_dir.join("standalone");
+    // NOTE: The interpreter must export the lua API for the module to use
+    run(Command::new(cc)
+        .arg(manifest_dir.join("tests/lua/standalone.c"))
+        .arg("-I")
+        .arg(lua_dir.join("include"))
+        .arg(lua_dir.join("lib/liblua5.4.a"))
+        .args(["-Wl,-E", "-lm", "-ldl", "-o"])
+        .arg(&interpreter));
+    let output = run(Command::new(&interpreter)
+        .arg(manifest_dir.join("tests/lua/require_entry_point.lua"))
+        .arg(target_dir.join(PROFILE).join("examples")));
+    assert_eq!(output.trim(), "loaded entry_point");
+}
@@ -0,0 +1,767 @@
+mod common;
+
+use common::{check, lua_with};
+use luao3::prelude::*;
+use luao3::Callback;
+use mlua::{Lua, Variadic};
+
+#[lua_function]
+fn resize(name: String, width: u32, height: Option<u32>) -> mlua::Result<String> {
+    Ok(format!("{name}:{width}x{}", height.unwrap_or(width)))
+}
+
+#[lua_function]
+fn sum(_lua: &Lua, values: Variadic<f64>) -> mlua::Result<f64> {
+    Ok(values.iter().sum())
+}
+
+/// Not actually a `Result`, despite the name
+#[derive(ToLua)]
+struct SearchResult {
+    matches: usize,
+}
+
+#[lua_function]
+fn search(text: String, term: String) -> SearchResult {
+    SearchResult {
+        matches: text.matches(&term).count(),
+    }
+}
+
+luao3::declare_simple_module! {
+    name => funcs,
+    members => {
+        fn resize,
+        fn sum,
+        fn search,
+    }
+}
+
+fn run(lua: &Lua, code: &str) -> mlua::Result<String> {
+    lua.globals().set("funcs", funcs(lua)?)?;
+    lua.load(code).eval()
+}
+
+#[test]
+fn convert_args() {
+    let lua = Lua::new();
+    assert_eq!(
+        run(&lua, "return funcs.resize('img', 3, 4)").unwrap(),
+        "img:3x4"
+    );
+    assert_eq!(
+        run(&lua, "return funcs.resize('img', 3)").unwrap(),
+        "img:3x3"
+    );
+    assert_eq!(
+        run(&lua, "return tostring(funcs.sum(1, 2, 3.5))").unwrap(),
+        "6.5"
+    );
+    assert_eq!(
+        run(
+            &lua,
+            "return tostring(funcs.search('banana', 'an').matches)"
+        )
+        .unwrap(),
+        "2"
+    );
+}
+
+#[test]
+fn bad_argument_messages() {
+    let lua = Lua::new();
+    let check = |code: &str, expected: &str| {
+        let err = run(&lua, code).unwrap_err().to_string();
+        assert!(
+            err.contains(expected),
+            "{err:?} should contain {expected:?}"
+        );
+    };
+    check(
+        "return funcs.resize('img', 'wide')",
+        "bad argument #2 to 'resize' (number expected, got string)",
+    );
+    check(
+        "return funcs.resize({}, 3)",
+        "bad argument #1 to 'resize' (string expected, got table)",
+    );
+    check(
+        "return funcs.resize('img', -1)",
+        "bad argument #2 to 'resize' (out of range)",
+    );
+    check(
+        "return funcs.sum(1, {})",
+        "bad argument #2 to 'sum' (number expected, got table)",
+    );
+    check(
+        "return funcs.sum(1, 2, 3, {})",
+        "bad argument #4 to 'sum' (number expected, got table)",
+    );
+}
+
+#[test]
+fn original_function_callable() {
+    assert_eq!(resize("img".into(), 3, None).unwrap(), "img:3x3");
+    let lua = Lua::new();
+    assert_eq!(sum(&lua, Variadic::from_iter([1.0, 2.0])).unwrap(), 3.0);
+}
+
+#[lua_function]
+fn parse_int(text: String) -> Result<i32, std::num::ParseIntError> {
+    text.parse()
+}
+
+#[lua_function]
+fn first_char(text: String) -> Option<String> {
+    text.chars().next().map(String::from)
+}
+
+#[lua_function]
+fn nothing() {}
+
+luao3::declare_simple_module! {
+    name => returns,
+    members => {
+        fn parse_int,
+        fn first_char,
+        fn nothing,
+    }
+}
+
+#[test]
+fn flexible_return_types() {
+    let lua = lua_with("returns", returns);
+    check(&lua, "return returns.parse_int('12') == 12");
+    check(&lua, "return returns.first_char('') == nil");
+    check(&lua, "return returns.first_char('abc') == 'a'");
+    check(&lua, "return select('#', returns.nothing()) == 0");
+    let err = lua
+        .load("return returns.parse_int('twelve')")
+        .exec()
+        .unwrap_err();
+    assert!(err.to_string().contains("invalid digit"), "{err}");
+}
+
+fn io_errno(err: &std::io::Error) -> Option<i32> {
+    err.raw_os_error()
+}
+
+#[lua_function(returns = "nil_err", error_code = "io_errno")]
+fn read_file(path: String) -> std::io::Result<String> {
+    std::fs::read_to_string(path)
+}
+
+#[lua_function(returns = "nil_err")]
+fn checked_div(a: i64, b: i64) -> Result<i64, String> {
+    a.checked_div(b).ok_or_else(|| "division by zero".into())
+}
+
+luao3::declare_simple_module! {
+    name => nil_err,
+    members => {
+        fn read_file,
+        fn checked_div,
+    }
+}
+
+#[test]
+fn nil_err_convention() {
+    let lua = lua_with("nil_err", nil_err);
+    check(&lua, "return nil_err.checked_div(7, 2) == 3");
+    check(
+        &lua,
+        r#"
+        local res, err = nil_err.checked_div(7, 0)
+        return res == nil and err == "division by zero"
+        "#,
+    );
+    check(
+        &lua,
+        r#"
+        local res, err, code = nil_err.read_file("/this/file/does/not/exist")
+        return res == nil and type(err) == "string" and type(code) == "number"
+        "#,
+    );
+}
+
+#[derive(LuaUserData)]
+struct Account {
+    balance: i64,
+}
+
+#[lua_function]
+fn new_account(balance: i64) -> Account {
+    Account { balance }
+}
+
+#[lua_function]
+fn balance(account: &Account) -> i64 {
+    account.balance
+}
+
+#[lua_function]
+fn transfer(from: &mut Account, to: &mut Account, amount: i64) {
+    from.balance -= amount;
+    to.balance += amount;
+}
+
+luao3::declare_simple_module! {
+    name => accounts,
+    members => {
+        fn new_account,
+        fn balance,
+        fn transfer,
+    }
+}
+
+#[test]
+fn borrowed_userdata() {
+    let lua = lua_with("accounts", accounts);
+    check(
+        &lua,
+        r#"
+        local a, b = accounts.new_account(10), accounts.new_account(0)
+        accounts.transfer(a, b, 3)
+        return accounts.balance(a) == 7 and accounts.balance(b) == 3 and b.balance == 3
+        "#,
+    );
+    let check_err = |code: &str, expected: &str| {
+        let err = lua.load(code).exec().unwrap_err().to_string();
+        assert!(
+            err.contains(expected),
+            "{err:?} should contain {expected:?}"
+        );
+    };
+    check_err(
+        "accounts.balance({})",
+        "bad argument #1 to 'balance' (Account expected, got table)",
+    );
+    check_err(
+        "accounts.transfer(accounts.new_account(1), nil, 1)",
+        "bad argument #2 to 'transfer' (Account expected, got nil)",
+    );
+    check_err(
+        "local a = accounts.new_account(1); accounts.transfer(a, a, 1)",
+        "bad argument #2 to 'transfer' (Account already borrowed)",
+    );
+}
+
+#[lua_function]
+fn map_values<'lua>(values: Vec<i64>, func: Callback<'lua, i64, i64>) -> mlua::Result<Vec<i64>> {
+    values.into_iter().map(|value| func.call(value)).collect()
+}
+
+#[derive(FromLua)]
+struct Button<'lua> {
+    label: String,
+    on_click: Callback<'lua, String, String>,
+}
+
+#[lua_function]
+fn click(button: Button) -> mlua::Result<String> {
+    button.on_click.call(button.label)
+}
+
+#[lua_function]
+fn apply_twice(func: Callback<'_, i64, i64>) -> mlua::Result<i64> {
+    func.call(func.call(1)?)
+}
+
+luao3::declare_simple_module! {
+    name => callbacks,
+    members => {
+        fn map_values,
+        fn click,
+        fn apply_twice,
+    }
+}
+
+#[test]
+fn typed_callbacks() {
+    let lua = lua_with("callbacks", callbacks);
+    check(
+        &lua,
+        r#"
+        local res = callbacks.map_values({1, 2, 3}, function(x) return x * 2 end)
+        return #res == 3 and res[1] == 2 and res[3] == 6
+        "#,
+    );
+    check(
+        &lua,
+        r#"
+        local function on_click(label) return "clicked " .. label end
+        return callbacks.click({label = "ok", on_click = on_click}) == "clicked ok"
+        "#,
+    );
+    check(
+        &lua,
+        "return callbacks.apply_twice(function(x) return x + 10 end) == 21",
+    );
+    let check_err = |code: &str, expected: &str| {
+        let err = lua.load(code).exec().unwrap_err().to_string();
+        assert!(
+            err.contains(expected),
+            "{err:?} should contain {expected:?}"
+        );
+    };
+    check_err(
+        "callbacks.map_values({1}, 'not a function')",
+        "bad argument #2 to 'map_values' (function expected, got string)",
+    );
+    check_err(
+        "callbacks.map_values({1}, function(x) return {} end)",
+        "bad return value from callback (argument #2 to 'map_values'): number expected, got table",
+    );
+    check_err(
+        "callbacks.apply_twice(function(x) return 'nope' end)",
+        "bad return value from callback (argument #1 to 'apply_twice')",
+    );
+    check_err(
+        "callbacks.click({label = 'ok', on_click = function() return nil end})",
+        "bad return value from callback (field on_click of",
+    );
+}
+
+#[derive(Default)]
+struct Database {
+    entries: Vec<String>,
+}
+
+#[lua_function]
+fn db_insert(#[lua(app_data)] db: &mut Database, entry: String) -> usize {
+    db.entries.push(entry);
+    db.entries.len()
+}
+
+#[lua_function]
+fn db_get(#[lua(app_data)] db: &Database, index: usize) -> Option<String> {
+    db.entries.get(index).cloned()
+}
+
+#[lua_function]
+fn db_update(
+    #[lua(app_data)] db: &mut Database,
+    update: Callback<'_, String, String>,
+) -> mlua::Result<()> {
+    for entry in db.entries.iter_mut() {
+        *entry = update.call(entry.clone())?;
+    }
+    Ok(())
+}
+
+#[lua_function]
+fn db_describe(account: &Account, #[lua(app_data)] db: &Database) -> String {
+    format!("{} entries, balance {}", db.entries.len(), account.balance)
+}
+
+luao3::declare_simple_module! {
+    name => database,
+    members => {
+        fn db_insert,
+        fn db_get,
+        fn db_update,
+        fn db_describe,
+        fn new_account,
+    }
+}
+
+#[test]
+fn app_data_params() {
+    let lua = lua_with("database", database);
+    let err = lua
+        .load("database.db_get(0)")
+        .exec()
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains("'db_get' requires app data of type Database, which was never installed"),
+        "{err}"
+    );
+    lua.set_app_data(Database::default());
+    check(
+        &lua,
+        "return database.db_insert('first') == 1 and database.db_insert('second') == 2",
+    );
+    check(
+        &lua,
+        "return database.db_get(1) == 'second' and database.db_get(5) == nil",
+    );
+    check(&lua, "database.db_update(function(entry) return entry:upper() end); return database.db_get(0) == 'FIRST'");
+    check(
+        &lua,
+        "return database.db_describe(database.new_account(5)) == '2 entries, balance 5'",
+    );
+    // Re-entrant borrows are errors instead of panics
+    let err = lua
+        .load("database.db_update(function(entry) return database.db_get(0) end)")
+        .exec()
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains("'db_get' can't use the app data of type Database, because it is already mutably borrowed"),
+        "{err}"
+    );
+    check(&lua, "return database.db_insert('third') == 3");
+    assert_eq!(lua.app_data_ref::<Database>().unwrap().entries.len(), 3);
+}
+
+#[derive(Clone, Debug, PartialEq, LuaUserData)]
+struct Point {
+    x: f64,
+    y: f64,
+}
+
+#[lua_function]
+fn point_xy(x: f64, y: f64) -> Point {
+    Point { x, y }
+}
+
+#[lua_function]
+fn point_table(xy: [f64; 2]) -> Point {
+    Point { x: xy[0], y: xy[1] }
+}
+
+#[lua_function]
+fn point_copy(other: &Point) -> Point {
+    other.clone()
+}
+
+#[lua_function]
+fn describe_number(value: i64) -> String {
+    format!("number {value}")
+}
+
+#[lua_function]
+fn describe_text(value: String) -> String {
+    format!("text {value}")
+}
+
+type Coordinates = Variadic<f64>;
+
+#[lua_function]
+fn point_path(coordinates: Coordinates) -> usize {
+    coordinates.len() / 2
+}
+
+#[lua_function]
+fn point_pair(pair: (f64, f64)) -> Point {
+    Point {
+        x: pair.0,
+        y: pair.1,
+    }
+}
+
+luao3::declare_simple_module! {
+    name => overloads,
+    members => {
+        overload point { point_copy, point_table, point_xy },
+        overload point_alias { point_xy } as "alias",
+        overload describe { describe_number, describe_text },
+        fn point_path,
+        fn point_pair,
+    }
+}
+
+#[test]
+fn overloaded_functions() {
+    let lua = lua_with("overloads", overloads);
+    check(
+        &lua,
+        "local p = overloads.point(1, 2); return p.x == 1 and p.y == 2",
+    );
+    check(
+        &lua,
+        "local p = overloads.point({3, 4}); return p.x == 3 and p.y == 4",
+    );
+    check(
+        &lua,
+        "local p = overloads.point(overloads.point(5, 6)); return p.x == 5 and p.y == 6",
+    );
+    check(&lua, "return overloads.alias(7, 8).y == 8");
+    let err = lua
+        .load("overloads.point('x', {})")
+        .exec()
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains(
+            "no overload of 'point' matches the arguments (string, table), expected one of:\n    \
+            point_xy(x: f64, y: f64)\n    \
+            point_copy(other: &Point)\n    \
+            point_table(xy: [f64; 2])"
+        ),
+        "{err}"
+    );
+    // Extra arguments don't match
+    assert!(lua.load("overloads.point(1, 2, 3)").exec().is_err());
+    // Strings aren't coerced into numbers when choosing an overload
+    check(&lua, "return overloads.describe(1) == 'number 1'");
+    check(&lua, "return overloads.describe('1') == 'text 1'");
+    // Functions that aren't overloaded still ignore extra arguments
+    check(&lua, "return overloads.alias(1, 2).x == 1");
+    check(&lua, "return overloads.point_pair(1, 2, 3).y == 2");
+    check(&lua, "return overloads.point_path(1, 2, 3, 4) == 2");
+}
+
+#[lua_function(mut)]
+fn next_id(#[lua(state)] counter: &mut u32) -> u32 {
+    *counter += 1;
+    *counter
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: std::collections::HashMap<String, String>,
+}
+
+#[lua_function]
+fn cache_put(#[lua(module_state)] cache: &mut Cache, key: String, value: String) {
+    cache.entries.insert(key, value);
+}
+
+#[lua_function]
+fn cache_get(#[lua(module_state)] cache: &Cache, key: String) -> Option<String> {
+    cache.entries.get(&key).cloned()
+}
+
+luao3::declare_simple_module! {
+    name => stateful,
+    members => {
+        fn next_id,
+        fn cache_put,
+        fn cache_get,
+    },
+    state => Cache::default(),
+}
+
+luao3::declare_simple_module! {
+    name => missing_state,
+    members => {
+        fn cache_get,
+    },
+}
+
+#[test]
+fn stateful_functions() {
+    let lua = lua_with("a", stateful);
+    lua.globals().set("b", stateful(&lua).unwrap()).unwrap();
+    check(
+        &lua,
+        "return a.next_id() == 1 and a.next_id() == 2 and b.next_id() == 1",
+    );
+    check(
+        &lua,
+        "a.cache_put('key', 'value'); return a.cache_get('key') == 'value'",
+    );
+    // Each module has its own state
+    check(&lua, "return b.cache_get('key') == nil");
+    let err = missing_state(&lua).unwrap_err().to_string();
+    assert!(
+        err.contains("'cache_get' requires module state, but none was declared"),
+        "{err}"
+    );
+    let err = <next_id as luao3::LuaFunction>::call(&lua, mlua::MultiValue::new())
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains("'next_id' uses state that only exists in the lua function"),
+        "{err}"
+    );
+}
+
+#[lua_function]
+fn count_up(n: u32) -> impl Iterator<Item = u32> {
+    1..=n
+}
+
+#[lua_function]
+fn split_words(text: String) -> mlua::Result<impl Iterator<Item = (usize, String)>> {
+    let words = text
+        .split_whitespace()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    Ok(words.into_iter().enumerate())
+}
+
+#[lua_function(returns = "nil_err")]
+fn read_lines(path: String) -> std::io::Result<impl Iterator<Item = String>> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(text
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>()
+        .into_iter())
+}
+
+#[lua_function]
+fn evens(limit: u32) -> luao3::LuaIter<impl Iterator<Item = u32>> {
+    luao3::LuaIter::new((0..limit).filter(|x| x % 2 == 0))
+}
+
+luao3::declare_simple_module! {
+    name => iterators,
+    members => {
+        fn count_up,
+        fn split_words,
+        fn read_lines,
+        fn evens,
+    }
+}
+
+#[test]
+fn rust_iterators() {
+    let lua = lua_with("iterators", iterators);
+    check(
+        &lua,
+        r#"
+        local total = 0
+        for x in iterators.count_up(4) do total = total + x end
+        return total == 10
+        "#,
+    );
+    check(
+        &lua,
+        r#"
+        local res = {}
+        for idx, word in iterators.split_words("hello lua world") do
+            res[#res + 1] = idx .. "=" .. word
+        end
+        return table.concat(res, ",") == "0=hello,1=lua,2=world"
+        "#,
+    );
+    check(
+        &lua,
+        r#"
+        local res = {}
+        for x in iterators.evens(7) do res[#res + 1] = x end
+        return table.concat(res, ",") == "0,2,4,6"
+        "#,
+    );
+    check(
+        &lua,
+        r#"
+        local iter = iterators.count_up(1)
+        return iter() == 1 and iter() == nil and iter() == nil
+        "#,
+    );
+    check(
+        &lua,
+        r#"
+        local found = false
+        for line in iterators.read_lines("Cargo.toml") do
+            if line == "[package]" then found = true end
+        end
+        local res, err = iterators.read_lines("/this/file/does/not/exist")
+        return found and res == nil and type(err) == "string"
+        "#,
+    );
+}
+
+#[lua_function]
+fn boom(msg: String) {
+    panic!("{msg}")
+}
+
+#[lua_function]
+fn boom_after(n: u32) -> impl Iterator<Item = u32> {
+    (1..=n).chain(std::iter::from_fn(|| panic!("iterator exhausted")))
+}
+
+#[lua_function(catch_panic = false)]
+fn uncaught_boom() {
+    panic!("uncaught")
+}
+
+luao3::declare_simple_module! {
+    name => panics,
+    members => {
+        fn boom,
+        fn boom_after,
+        fn uncaught_boom,
+    }
+}
+
+luao3::declare_simple_module! {
+    name => backtrace_panics,
+    members => {
+        fn boom,
+        fn boom_after,
+    },
+    panic_backtraces => true,
+}
+
+luao3::declare_simple_module! {
+    name => unwinding_panics,
+    members => {
+        fn boom,
+        fn boom_after,
+    },
+    catch_panics => false,
+}
+
+#[test]
+fn catch_panics() {
+    luao3::panic::install_hook();
+    let lua = lua_with("panics", panics);
+    let (ok, msg) = lua
+        .load("local ok, err = pcall(panics.boom, 'kaboom'); return ok, tostring(err)")
+        .eval::<(bool, String)>()
+        .unwrap();
+    assert!(!ok);
+    assert!(msg.contains("Rust panic in 'boom': kaboom"), "{msg}");
+    assert!(msg.contains("tests/func.rs"), "{msg}");
+    assert!(!msg.contains("backtrace:"), "{msg}");
+    // The rust side can still get at the details
+    let err = lua.load("panics.boom('again')").exec().unwrap_err();
+    let panic = match err {
+        mlua::Error::CallbackError { ref cause, .. } => match **cause {
+            mlua::Error::ExternalError(ref cause) => {
+                cause.downcast_ref::<luao3::panic::RustPanic>().unwrap()
+            }
+            ref other => panic!("unexpected cause: {other:?}"),
+        },
+        ref other => panic!("unexpected error: {other:?}"),
+    };
+    assert_eq!(panic.function(), "boom");
+    assert_eq!(panic.message(), "again");
+    // Iterators handle panics like the function that returned them
+    let msg = lua
+        .load("local iter = panics.boom_after(1); iter(); return tostring(select(2, pcall(iter)))")
+        .eval::<String>()
+        .unwrap();
+    assert!(
+        msg.contains("Rust panic in 'boom_after': iterator exhausted"),
+        "{msg}"
+    );
+    assert!(!msg.contains("backtrace:"), "{msg}");
+
+    lua.globals()
+        .set("backtrace_panics", backtrace_panics(&lua).unwrap())
+        .unwrap();
+    let msg = lua
+        .load("return tostring(select(2, pcall(backtrace_panics.boom, 'kaboom')))")
+        .eval::<String>()
+        .unwrap();
+    assert!(msg.contains("backtrace:"), "{msg}");
+    let msg = lua
+        .load("return tostring(select(2, pcall(backtrace_panics.boom_after(0))))")
+        .eval::<String>()
+        .unwrap();
+    assert!(msg.contains("backtrace:"), "{msg}");
+}
+
+#[test]
+fn uncaught_panics() {
+    let lua = lua_with("panics", panics);
+    lua.globals()
+        .set("unwinding_panics", unwinding_panics(&lua).unwrap())
+        .unwrap();
+    for code in [
+        "panics.uncaught_boom()",
+        "unwinding_panics.boom('x')",
+        "unwinding_panics.boom_after(0)()",
+    ] {
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| lua.load(code).exec()));
+        assert!(res.is_err(), "{code} should resume unwinding");
+    }
+}
@@ -0,0 +1,81 @@
+use luao3::prelude::*;
+use mlua::{Lua, Table};
+
+#[lua_import(global = "on_tick")]
+fn on_tick(lua: &Lua, dt: f64) -> mlua::Result<bool>;
+
+#[lua_import(path = "game.hooks.describe")]
+fn describe(lua: &Lua, name: String, level: u32) -> mlua::Result<String>;
+
+#[lua_import(field = "area")]
+fn area(shape: &Table, scale: f64) -> mlua::Result<f64>;
+
+#[lua_import]
+fn missing(lua: &Lua) -> mlua::Result<()>;
+
+#[lua_import(table = "game.hooks")]
+trait GameHooks {
+    fn describe(&self, name: String, level: u32) -> mlua::Result<String>;
+    #[lua(name = "on_tick")]
+    fn tick(&self) -> mlua::Result<u32>;
+    fn not_imported(&self) -> u32 {
+        42
+    }
+}
+
+const SCRIPT: &str = r#"
+ticks = 0
+function on_tick(dt)
+    ticks = ticks + 1
+    return dt < 1
+end
+game = { hooks = {} }
+function game.hooks.describe(name, level) return name .. "@" .. level end
+function game.hooks.on_tick() return {} end
+shape = { area = function(scale) return 4 * scale end }
+"#;
+
+#[test]
+fn imported_functions() {
+    let lua = Lua::new();
+    lua.load(SCRIPT).exec().unwrap();
+    assert!(on_tick(&lua, 0.5).unwrap());
+    assert!(!on_tick(&lua, 2.0).unwrap());
+    assert_eq!(lua.globals().get::<_, u32>("ticks").unwrap(), 2);
+    assert_eq!(describe(&lua, "hero".into(), 3).unwrap(), "hero@3");
+    let shape: Table = lua.globals().get("shape").unwrap();
+    assert_eq!(area(&shape, 2.0).unwrap(), 8.0);
+    // Traits are implemented for the `Lua` itself
+    assert_eq!(lua.describe("villain".into(), 9).unwrap(), "villain@9");
+    assert_eq!(lua.not_imported(), 42);
+}
+
+#[test]
+fn import_errors() {
+    let lua = Lua::new();
+    let check = |err: mlua::Error, expected: &str| {
+        let err = err.to_string();
+        assert!(
+            err.contains(expected),
+            "{err:?} should contain {expected:?}"
+        );
+    };
+    check(
+        missing(&lua).unwrap_err(),
+        "lua function 'missing' is not defined",
+    );
+    check(
+        describe(&lua, "hero".into(), 3).unwrap_err(),
+        "can't find lua function 'game.hooks.describe' ('game' is nil, not a table)",
+    );
+    lua.load(SCRIPT).exec().unwrap();
+    lua.load("on_tick = 5").exec().unwrap();
+    check(
+        on_tick(&lua, 0.5).unwrap_err(),
+        "lua function 'on_tick' should be a function, not number",
+    );
+    check(
+        lua.tick().unwrap_err(),
+        "bad return value from lua function 'game.hooks.on_tick' (number expected, got table)",
+    );
+}
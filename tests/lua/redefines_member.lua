@@ -0,0 +1,3 @@
+return {
+    upper = function(text) return text end,
+}
@@ -0,0 +1,8 @@
+-- Loads the `entry_point` example, given the directory containing the shared library
+local dir = ...
+package.cpath = dir .. "/lib?.so"
+local entry_point = require "entry_point"
+assert(entry_point.greet("lua") == "hello lua", "unexpected greeting")
+assert(entry_point.VERSION == 3, "unexpected version")
+assert(package.loaded.entry_point == entry_point)
+print("loaded entry_point")
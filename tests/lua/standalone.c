@@ -0,0 +1,32 @@
+/*
+ * A minimal standalone lua interpreter, used to load modules built as shared libraries.
+ *
+ * Usage: standalone <script> [args...]
+ */
+#include <stdio.h>
+
+#include "lauxlib.h"
+#include "lua.h"
+#include "lualib.h"
+
+int main(int argc, char **argv) {
+    if (argc < 2) {
+        fprintf(stderr, "usage: %s <script> [args...]\n", argv[0]);
+        return 2;
+    }
+    lua_State *L = luaL_newstate();
+    luaL_openlibs(L);
+    if (luaL_loadfile(L, argv[1]) != LUA_OK) {
+        fprintf(stderr, "%s\n", lua_tostring(L, -1));
+        return 1;
+    }
+    for (int i = 2; i < argc; i++) {
+        lua_pushstring(L, argv[i]);
+    }
+    if (lua_pcall(L, argc - 2, 0, 0) != LUA_OK) {
+        fprintf(stderr, "%s\n", lua_tostring(L, -1));
+        return 1;
+    }
+    lua_close(L);
+    return 0;
+}
@@ -0,0 +1,11 @@
+-- Helpers written in lua, using the rust members of the module
+local module = ...
+
+local function shout(text)
+    return upper(text) .. "!"
+end
+
+return {
+    shout = shout,
+    greeting = shout("hello " .. module.NAME),
+}
@@ -0,0 +1,106 @@
+use luao3::prelude::*;
+use luao3::LuaImpl;
+use mlua::{Lua, Table};
+
+#[lua_trait]
+trait Plugin {
+    fn name(&self) -> mlua::Result<String>;
+    #[lua(name = "on_message")]
+    fn handle(&mut self, sender: String, text: String) -> mlua::Result<Option<String>>;
+    fn priority(&self) -> mlua::Result<i32> {
+        Ok(0)
+    }
+    fn weight(&self, [base, bonus]: [i32; 2]) -> mlua::Result<i32> {
+        Ok(base + bonus + self.priority()?)
+    }
+    fn label(&self) -> mlua::Result<String> {
+        // Items nested in a default keep their own meaning of `Self`
+        struct Label(String);
+        impl Label {
+            fn new(name: &str) -> Self {
+                Label(shout(name))
+            }
+        }
+        fn shout(text: &str) -> String {
+            text.to_uppercase()
+        }
+        Ok(Label::new(&self.name()?).0)
+    }
+}
+
+fn load_plugin<'lua>(lua: &'lua Lua, code: &str) -> LuaImpl<Table<'lua>> {
+    LuaImpl::new(lua.load(code).eval().unwrap())
+}
+
+#[test]
+fn lua_plugins() {
+    let lua = Lua::new();
+    let mut echo = load_plugin(
+        &lua,
+        r#"
+        local Echo = {}
+        Echo.__index = Echo
+        function Echo:name() return "echo:" .. self.prefix end
+        function Echo:on_message(sender, text) return self.prefix .. sender .. ": " .. text end
+        function Echo:priority() return 10 end
+        return setmetatable({ prefix = ">" }, Echo)
+        "#,
+    );
+    assert_eq!(echo.name().unwrap(), "echo:>");
+    assert_eq!(
+        echo.handle("bob".into(), "hi".into()).unwrap().as_deref(),
+        Some(">bob: hi")
+    );
+    assert_eq!(echo.priority().unwrap(), 10);
+    assert_eq!(echo.weight([1, 2]).unwrap(), 13);
+
+    // Optional methods fall back to the trait's default
+    let mut quiet = load_plugin(
+        &lua,
+        "return { name = function(self) return 'quiet' end, on_message = function() end }",
+    );
+    assert_eq!(quiet.name().unwrap(), "quiet");
+    assert_eq!(quiet.handle("bob".into(), "hi".into()).unwrap(), None);
+    assert_eq!(quiet.priority().unwrap(), 0);
+    assert_eq!(quiet.label().unwrap(), "QUIET");
+    let weighted = load_plugin(
+        &lua,
+        "return { priority = function() return 5 end, weight = function(self, pair) return pair[1] * pair[2] * 10 end }",
+    );
+    assert_eq!(weighted.weight([3, 4]).unwrap(), 120);
+    let unweighted = load_plugin(&lua, "return { priority = function() return 5 end }");
+    assert_eq!(unweighted.weight([3, 4]).unwrap(), 12);
+
+    // Required methods are an error
+    let broken = load_plugin(&lua, "return { priority = 'high' }");
+    let err = broken.name().unwrap_err().to_string();
+    assert!(
+        err.contains("lua object doesn't define the method 'name'"),
+        "{err}"
+    );
+    let err = broken.priority().unwrap_err().to_string();
+    assert!(
+        err.contains("lua function 'priority' should be a function, not string"),
+        "{err}"
+    );
+}
+
+/// Rust implementations of the trait are unaffected
+struct Native;
+impl Plugin for Native {
+    fn name(&self) -> mlua::Result<String> {
+        Ok("native".into())
+    }
+    fn handle(&mut self, _sender: String, _text: String) -> mlua::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[test]
+fn native_plugins() {
+    let plugins: Vec<Box<dyn Plugin>> = vec![Box::new(Native)];
+    assert_eq!(plugins[0].name().unwrap(), "native");
+    assert_eq!(plugins[0].priority().unwrap(), 0);
+    assert_eq!(plugins[0].weight([1, 2]).unwrap(), 3);
+    assert_eq!(plugins[0].label().unwrap(), "NATIVE");
+}
@@ -0,0 +1,223 @@
+mod common;
+
+use common::{check, lua_with};
+use luao3::prelude::*;
+use mlua::{AnyUserData, Lua};
+
+#[derive(Debug, Clone, PartialEq)]
+struct Counter {
+    count: i64,
+    step: i64,
+}
+
+#[lua_methods]
+impl Counter {
+    fn new(step: Option<i64>) -> Counter {
+        Counter {
+            count: 0,
+            step: step.unwrap_or(1),
+        }
+    }
+    fn get(&self) -> i64 {
+        self.count
+    }
+    fn increment(&mut self, times: Option<i64>) -> i64 {
+        self.count += self.step * times.unwrap_or(1);
+        self.count
+    }
+    #[lua(name = "reset_to")]
+    fn reset(&mut self, count: i64) {
+        self.count = count;
+    }
+    #[lua(returns = "nil_err")]
+    fn checked_step(&self, divisor: i64) -> Result<i64, String> {
+        self.step
+            .checked_div(divisor)
+            .ok_or_else(|| "division by zero".into())
+    }
+    #[lua(skip)]
+    #[allow(dead_code)]
+    fn hidden(&self) {}
+    fn explode(&self) {
+        panic!("exploded at {}", self.count)
+    }
+}
+
+#[test]
+fn methods() {
+    let lua = Lua::new();
+    lua.globals().set("counter", Counter::new(Some(2))).unwrap();
+    check(&lua, "return counter:get() == 0");
+    check(&lua, "return counter:increment() == 2");
+    check(&lua, "return counter:increment(3) == 8");
+    check(&lua, "counter:reset_to(5); return counter:get() == 5");
+    check(&lua, "return counter.new(7):increment() == 7");
+    check(&lua, "return counter:checked_step(2) == 1");
+    check(
+        &lua,
+        "return select(2, counter:checked_step(0)) == 'division by zero'",
+    );
+    check(
+        &lua,
+        "return not pcall(function() return counter.hidden end)",
+    );
+    check(
+        &lua,
+        "return not pcall(function() return counter.reset end)",
+    );
+    check(&lua, "local ok, err = pcall(counter.explode, counter); return not ok and tostring(err):find('exploded at 5') ~= nil");
+    let err = lua
+        .load("counter:increment('lots')")
+        .exec()
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains("bad argument #1 to 'increment' (number expected, got string)"),
+        "{err}"
+    );
+    let counter = lua.globals().get::<_, AnyUserData>("counter").unwrap();
+    assert_eq!(
+        *counter.borrow::<Counter>().unwrap(),
+        Counter { count: 5, step: 2 }
+    );
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+struct Vec2 {
+    x: f64,
+    y: f64,
+}
+
+impl std::fmt::Display for Vec2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({}, {})", self.x, self.y)
+    }
+}
+
+impl std::ops::Add for Vec2 {
+    type Output = Vec2;
+    fn add(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x + other.x, self.y + other.y)
+    }
+}
+
+impl std::ops::Sub for Vec2 {
+    type Output = Vec2;
+    fn sub(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x - other.x, self.y - other.y)
+    }
+}
+
+impl std::ops::Neg for Vec2 {
+    type Output = Vec2;
+    fn neg(self) -> Vec2 {
+        Vec2::new(-self.x, -self.y)
+    }
+}
+
+#[lua_methods(derive(Display, PartialEq, PartialOrd, Add, Sub, Neg))]
+impl Vec2 {
+    fn new(x: f64, y: f64) -> Vec2 {
+        Vec2 { x, y }
+    }
+    #[lua(meta = "len")]
+    fn length(&self) -> f64 {
+        self.x.hypot(self.y)
+    }
+    #[lua(meta = "__index")]
+    fn index(&self, key: String) -> Option<f64> {
+        match &*key {
+            "x" => Some(self.x),
+            "y" => Some(self.y),
+            _ => None,
+        }
+    }
+    #[lua(meta = "call")]
+    fn scale(&self, factor: f64) -> Vec2 {
+        Vec2::new(self.x * factor, self.y * factor)
+    }
+    #[lua(meta = "concat")]
+    fn concat(lua: &Lua, a: mlua::Value, b: mlua::Value) -> mlua::Result<String> {
+        let tostring: mlua::Function = lua.globals().get("tostring")?;
+        Ok(tostring.call::<_, String>(a)? + &tostring.call::<_, String>(b)?)
+    }
+}
+
+#[test]
+fn metamethods() {
+    let lua = Lua::new();
+    lua.globals().set("a", Vec2::new(3.0, 4.0)).unwrap();
+    lua.globals().set("b", Vec2::new(1.0, 1.0)).unwrap();
+    check(&lua, "return tostring(a) == '(3, 4)'");
+    check(&lua, "return #a == 5");
+    check(&lua, "return a.x == 3 and a.y == 4 and a.z == nil");
+    check(&lua, "return tostring(a(2)) == '(6, 8)'");
+    check(&lua, "return tostring(a + b) == '(4, 5)'");
+    check(&lua, "return tostring(a - b) == '(2, 3)'");
+    check(&lua, "return tostring(-b) == '(-1, -1)'");
+    check(&lua, "return a == a(1) and a ~= b");
+    check(&lua, "return b < a and b <= a and not (a < b)");
+    check(&lua, "return 'vec: ' .. a == 'vec: (3, 4)'");
+}
+
+struct Fuse;
+
+#[lua_methods(panic_backtraces = true)]
+impl Fuse {
+    fn new() -> Fuse {
+        Fuse
+    }
+    fn blow(&self) {
+        panic!("blown")
+    }
+    fn blow_all() {
+        panic!("all blown")
+    }
+}
+
+luao3::declare_simple_module! {
+    name => fuses,
+    members => {
+        class Fuse,
+    },
+    panic_backtraces => true,
+}
+
+luao3::declare_simple_module! {
+    name => quiet_fuses,
+    members => {
+        class Fuse,
+    },
+}
+
+#[test]
+fn panic_backtraces() {
+    luao3::panic::install_hook();
+    let lua = lua_with("fuses", fuses);
+    lua.globals()
+        .set("quiet_fuses", quiet_fuses(&lua).unwrap())
+        .unwrap();
+    let error_message = |code: &str| {
+        lua.load(code)
+            .eval::<(bool, String)>()
+            .map(|(ok, msg)| {
+                assert!(!ok, "{code}");
+                msg
+            })
+            .unwrap()
+    };
+    // Methods use the options from the attribute
+    let msg = error_message(
+        "local ok, err = pcall(function() fuses.Fuse.new():blow() end); return ok, tostring(err)",
+    );
+    assert!(msg.contains("Rust panic in 'blow': blown"), "{msg}");
+    assert!(msg.contains("backtrace:"), "{msg}");
+    // Functions in class tables use the options of the module
+    let msg = error_message("local ok, err = pcall(fuses.Fuse.blow_all); return ok, tostring(err)");
+    assert!(msg.contains("Rust panic in 'blow_all': all blown"), "{msg}");
+    assert!(msg.contains("backtrace:"), "{msg}");
+    let msg =
+        error_message("local ok, err = pcall(quiet_fuses.Fuse.blow_all); return ok, tostring(err)");
+    assert!(msg.contains("Rust panic in 'blow_all': all blown"), "{msg}");
+    assert!(!msg.contains("backtrace:"), "{msg}");
+}
@@ -0,0 +1,443 @@
+mod common;
+
+use common::{check, lua_with};
+use luao3::prelude::*;
+use mlua::Lua;
+
+#[derive(Debug, Clone, Copy, PartialEq, ToLua, FromLua)]
+#[lua(constants)]
+enum Color {
+    Red,
+    Green,
+    Blue,
+}
+
+#[derive(ToLua)]
+struct Limits {
+    max_width: u32,
+    max_height: u32,
+}
+
+fn compute_limits() -> Limits {
+    Limits {
+        max_width: 640,
+        max_height: 480,
+    }
+}
+
+/// Values can't see the variables used to create the module
+fn lua() -> &'static str {
+    "not the lua state"
+}
+
+#[lua_function]
+fn is_red(color: Color) -> bool {
+    color == Color::Red
+}
+
+luao3::declare_simple_module! {
+    name => constants,
+    members => {
+        const VERSION = "1.2",
+        const MAX_LEVEL = 99,
+        value limits = compute_limits(),
+        value hygiene = lua(),
+        enum Color,
+        enum self::Color as "Colour",
+        fn is_red,
+    }
+}
+
+#[test]
+fn constant_members() {
+    let lua = lua_with("constants", constants);
+    check(
+        &lua,
+        "return constants.VERSION == '1.2' and constants.MAX_LEVEL == 99",
+    );
+    check(
+        &lua,
+        "return constants.limits.max_width == 640 and constants.limits.max_height == 480",
+    );
+    check(&lua, "return constants.hygiene == 'not the lua state'");
+    check(
+        &lua,
+        "return constants.Color.Red == 'Red' and constants.Colour.Blue == 'Blue'",
+    );
+    check(&lua, "return constants.is_red(constants.Color.Red) and not constants.is_red(constants.Color.Green)");
+    assert_eq!(
+        <Color as luao3::LuaEnum>::VARIANTS,
+        ["Red", "Green", "Blue"]
+    );
+}
+
+mod net {
+    use luao3::prelude::*;
+
+    #[lua_function]
+    pub fn get(url: String) -> String {
+        format!("GET {url}")
+    }
+
+    #[lua_function]
+    pub fn post(url: String) -> String {
+        format!("POST {url}")
+    }
+
+    luao3::declare_simple_module! {
+        name => net_module,
+        members => {
+            mod http {
+                fn get,
+                fn post,
+            },
+            const DEFAULT_PORT = 80,
+        },
+        vis => pub,
+    }
+}
+
+luao3::declare_simple_module! {
+    name => app,
+    members => {
+        module net::net_module as net,
+        /// Assorted helpers
+        ///
+        /// These are nested in a table.
+        mod util {
+            fn is_red,
+            mod colors {
+                enum Color,
+            },
+        },
+        const NAME = "app",
+    },
+    doc => "The whole application",
+}
+
+#[test]
+fn nested_modules() {
+    let lua = lua_with("app", app);
+    check(&lua, "return app.net.http.get('/index') == 'GET /index'");
+    check(
+        &lua,
+        "return app.net.http.post('/form') == 'POST /form' and app.net.DEFAULT_PORT == 80",
+    );
+    check(
+        &lua,
+        "return app.util.is_red(app.util.colors.Color.Red) and app.NAME == 'app'",
+    );
+}
+
+#[test]
+fn module_metadata() {
+    use luao3::module::{MemberKind, ModuleMember};
+    use luao3::LuaModule;
+    fn names(members: &[ModuleMember]) -> Vec<&'static str> {
+        members.iter().map(|member| member.name).collect()
+    }
+    assert_eq!(<app as LuaModule>::NAME, "app");
+    assert_eq!(<app as LuaModule>::DOC, Some("The whole application"));
+    assert_eq!(<constants as LuaModule>::DOC, None);
+    let members = <app as LuaModule>::MEMBERS;
+    assert_eq!(names(members), ["net", "util", "NAME"]);
+    match members[0].kind {
+        MemberKind::Module { name, members } => {
+            assert_eq!(name, "net_module");
+            assert_eq!(names(members), ["http", "DEFAULT_PORT"]);
+        }
+        other => panic!("Unexpected kind {other:?}"),
+    }
+    assert_eq!(
+        members[1].doc,
+        Some("Assorted helpers\n\nThese are nested in a table.")
+    );
+    match members[1].kind {
+        MemberKind::Submodule { members } => {
+            assert_eq!(names(members), ["is_red", "colors"]);
+            assert!(
+                matches!(members[0].kind, MemberKind::Function { signature } if signature == "is_red(color: Color)")
+            );
+        }
+        other => panic!("Unexpected kind {other:?}"),
+    }
+    assert!(matches!(members[2].kind, MemberKind::Value));
+    let members = <geometry as LuaModule>::MEMBERS;
+    assert!(matches!(
+        members[1].kind,
+        MemberKind::Class {
+            class_name: "Point"
+        }
+    ));
+    assert_eq!(names(members), ["Point", "Vector"]);
+}
+
+#[derive(Debug, Clone)]
+struct Point {
+    x: f64,
+    y: f64,
+}
+
+#[lua_methods]
+impl Point {
+    fn new(x: f64, y: f64) -> Point {
+        Point { x, y }
+    }
+    fn origin() -> Point {
+        Point::new(0.0, 0.0)
+    }
+    fn length(&self) -> f64 {
+        self.x.hypot(self.y)
+    }
+}
+
+luao3::declare_simple_module! {
+    name => geometry,
+    members => {
+        class Point,
+        type Point as "Vector",
+    }
+}
+
+#[test]
+fn class_members() {
+    let lua = lua_with("geometry", geometry);
+    check(&lua, "return geometry.Point.new(3, 4):length() == 5");
+    check(&lua, "return geometry.Vector.origin():length() == 0");
+    check(&lua, "return geometry.Point.length == nil");
+    check(
+        &lua,
+        "return tostring(geometry.Point.new(1, 2)):find('^Point: ') ~= nil",
+    );
+    let err = lua
+        .load("local _ = geometry.Point.origin() + 1")
+        .exec()
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("Point"), "{err}");
+}
+
+#[test]
+fn installed_modules() {
+    use luao3::LuaModule;
+    let lua = Lua::new();
+    constants::register_preload(&lua).unwrap();
+    check(
+        &lua,
+        "return package.loaded.constants == nil and constants == nil",
+    );
+    check(&lua, "return require('constants').VERSION == '1.2'");
+    check(
+        &lua,
+        "return require('constants') == package.loaded.constants",
+    );
+    let table = constants::install_global(&lua).unwrap();
+    assert_eq!(table.get::<_, String>("VERSION").unwrap(), "1.2");
+    check(&lua, "return constants == require('constants')");
+    geometry::install_global(&lua).unwrap();
+    check(&lua, "return geometry.Point.origin():length() == 0");
+}
+
+/// Text utilities, collected automatically
+#[lua_module(panic_backtraces = false)]
+mod text {
+    use luao3::prelude::*;
+
+    /// The separator used by `join`
+    pub const SEPARATOR: &str = ", ";
+
+    #[lua(skip)]
+    #[allow(dead_code)]
+    pub const INTERNAL_LIMIT: usize = 10;
+
+    /// Private constants are only exposed with `#[lua]`
+    #[lua]
+    const VERSION: &str = "1.0";
+
+    const MAX_WORDS: usize = 3;
+
+    /// Join the words with the separator
+    #[lua_function]
+    pub fn join(words: Vec<String>) -> String {
+        words[..words.len().min(MAX_WORDS)].join(SEPARATOR)
+    }
+
+    #[lua(name = "upper")]
+    #[lua_function]
+    pub fn to_upper(text: String) -> String {
+        text.to_uppercase()
+    }
+
+    #[lua(skip)]
+    #[lua_function]
+    #[allow(dead_code)]
+    pub fn hidden() {}
+
+    #[allow(dead_code)]
+    pub fn helper() {}
+
+    #[derive(Debug, Clone)]
+    pub struct Buffer {
+        parts: Vec<String>,
+    }
+
+    #[lua_methods]
+    impl Buffer {
+        fn new() -> Buffer {
+            Buffer { parts: Vec::new() }
+        }
+        fn push(&mut self, part: String) {
+            self.parts.push(part);
+        }
+        fn finish(&self) -> String {
+            self.parts.concat()
+        }
+    }
+}
+
+#[test]
+fn attribute_modules() {
+    use luao3::LuaModule;
+    let lua = lua_with("text", text::text);
+    check(
+        &lua,
+        "return text.join({'a', 'b'}) == 'a, b' and text.SEPARATOR == ', '",
+    );
+    check(
+        &lua,
+        "return text.upper('abc') == 'ABC' and text.to_upper == nil",
+    );
+    check(
+        &lua,
+        "return text.hidden == nil and text.helper == nil and text.INTERNAL_LIMIT == nil",
+    );
+    check(
+        &lua,
+        "return text.VERSION == '1.0' and text.MAX_WORDS == nil",
+    );
+    check(
+        &lua,
+        "local buf = text.Buffer.new(); buf:push('x'); buf:push('y'); return buf:finish() == 'xy'",
+    );
+    assert_eq!(
+        <text::text as LuaModule>::DOC,
+        Some("Text utilities, collected automatically")
+    );
+    let members = <text::text as LuaModule>::MEMBERS;
+    let names = members.iter().map(|member| member.name).collect::<Vec<_>>();
+    assert_eq!(names, ["SEPARATOR", "VERSION", "join", "upper", "Buffer"]);
+    assert_eq!(members[2].doc, Some("Join the words with the separator"));
+}
+
+#[lua_function]
+fn upper(text: String) -> String {
+    text.to_uppercase()
+}
+
+luao3::declare_simple_module! {
+    name => strings,
+    members => {
+        fn upper,
+        const NAME = "strings",
+        lua "tests/lua/strings.lua",
+    }
+}
+
+luao3::declare_simple_module! {
+    name => conflicting,
+    members => {
+        fn upper,
+        lua "tests/lua/redefines_member.lua",
+    }
+}
+
+#[test]
+fn lua_members() {
+    // NOTE: Precompiled chunks can only be loaded in unsafe mode
+    for lua in [Lua::new(), unsafe { Lua::unsafe_new() }] {
+        lua.globals()
+            .set("strings", strings(&lua).unwrap())
+            .unwrap();
+        check(&lua, "return strings.shout('hi') == 'HI!'");
+        check(&lua, "return strings.greeting == 'HELLO STRINGS!'");
+        check(&lua, "return upper == nil and shout == nil");
+        let err = conflicting(&lua).unwrap_err().to_string();
+        assert!(
+            err.contains(
+                "'tests/lua/redefines_member.lua' redefines the existing module member 'upper'"
+            ),
+            "{err}"
+        );
+    }
+}
+
+static CREATED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+fn track_creation() -> usize {
+    CREATED.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1
+}
+
+static CREATED_NIL: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+fn track_nil_creation() -> Option<usize> {
+    CREATED_NIL.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    None
+}
+
+luao3::declare_simple_module! {
+    name => lazy_module,
+    members => {
+        value tracked = track_creation(),
+        value nothing = track_nil_creation(),
+        fn upper,
+        mod nested {
+            fn is_red,
+            enum Color,
+        },
+        lua "tests/lua/strings.lua",
+        const NAME = "lazy",
+    },
+    lazy => true,
+}
+
+#[test]
+fn lazy_members() {
+    let lua = lua_with("lazy", lazy_module);
+    // The chunk only needs `upper` and `NAME`
+    assert_eq!(CREATED.load(std::sync::atomic::Ordering::SeqCst), 0);
+    check(
+        &lua,
+        "return rawget(lazy, 'upper') ~= nil and rawget(lazy, 'tracked') == nil",
+    );
+    check(
+        &lua,
+        "return lazy.tracked == 1 and lazy.tracked == 1 and rawget(lazy, 'tracked') == 1",
+    );
+    check(
+        &lua,
+        "return lazy.greeting == 'HELLO LAZY!' and lazy.missing == nil",
+    );
+    check(&lua, "return lazy.nothing == nil and lazy.nothing == nil");
+    assert_eq!(CREATED_NIL.load(std::sync::atomic::Ordering::SeqCst), 1);
+    check(
+        &lua,
+        "return rawget(lazy, 'nested') == nil and lazy.nested.is_red(lazy.nested.Color.Red)",
+    );
+    check(
+        &lua,
+        "local names = {}
+        for name in pairs(lazy) do table.insert(names, name) end
+        table.sort(names)
+        return table.concat(names, ',') == 'NAME,greeting,nested,shout,tracked,upper'",
+    );
+    assert_eq!(CREATED.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert_eq!(CREATED_NIL.load(std::sync::atomic::Ordering::SeqCst), 1);
+    // `pairs` still works if the global `next` is replaced
+    check(
+        &lua,
+        "local count = 0
+        next = nil
+        for _ in pairs(lazy) do count = count + 1 end
+        return count == 6",
+    );
+}
@@ -0,0 +1,31 @@
+#![cfg(feature = "registry")]
+use luao3::prelude::*;
+use mlua::Lua;
+
+#[lua_function]
+fn answer() -> i32 {
+    42
+}
+
+luao3::declare_simple_module! {
+    name => registered,
+    members => {
+        fn answer,
+    },
+    doc => "Found through the registry",
+}
+
+#[test]
+fn registered_modules() {
+    let module = luao3::module::find_registered_module("registered").unwrap();
+    assert_eq!(module.doc, Some("Found through the registry"));
+    assert_eq!(module.members.len(), 1);
+    assert!(luao3::module::registered_modules().any(|module| module.name == "registered"));
+    assert!(luao3::module::find_registered_module("missing").is_none());
+    let lua = Lua::new();
+    module.register_preload(&lua).unwrap();
+    assert!(lua
+        .load("return require('registered').answer() == 42")
+        .eval::<bool>()
+        .unwrap());
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use common::check;
+use luao3::prelude::*;
+use mlua::{AnyUserData, Lua};
+
+#[derive(Debug, Clone, PartialEq, LuaUserData)]
+#[lua(derive(Display))]
+struct Config {
+    name: String,
+    #[lua(get)]
+    id: u32,
+    #[lua(set)]
+    password: String,
+    #[lua(rename = "max")]
+    maximum: f64,
+    #[lua(skip)]
+    secret: Vec<u8>,
+}
+
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Config({})", self.name)
+    }
+}
+
+#[derive(Debug, LuaUserData)]
+#[lua(methods)]
+struct Player {
+    score: i64,
+}
+
+#[lua_methods(userdata = false)]
+impl Player {
+    fn add_points(&mut self, points: i64) {
+        self.score += points;
+    }
+}
+
+#[test]
+fn fields() {
+    let lua = Lua::new();
+    let config = Config {
+        name: "original".into(),
+        id: 7,
+        password: "hunter2".into(),
+        maximum: 1.5,
+        secret: vec![42],
+    };
+    lua.globals().set("config", config).unwrap();
+    check(
+        &lua,
+        "return config.name == 'original' and config.id == 7 and config.max == 1.5",
+    );
+    check(
+        &lua,
+        "config.name = 'changed'; config.max = 3; config.password = 'secure'; return true",
+    );
+    check(&lua, "return tostring(config) == 'Config(changed)'");
+    // Read-only, write-only, and skipped fields
+    check(&lua, "return not pcall(function() config.id = 8 end)");
+    check(
+        &lua,
+        "return not pcall(function() return config.password end)",
+    );
+    check(
+        &lua,
+        "return not pcall(function() return config.secret end)",
+    );
+    check(
+        &lua,
+        "return not pcall(function() return config.maximum end)",
+    );
+    check(&lua, "return not pcall(function() config.max = 'big' end)");
+    let config = lua.globals().get::<_, AnyUserData>("config").unwrap();
+    assert_eq!(
+        *config.borrow::<Config>().unwrap(),
+        Config {
+            name: "changed".into(),
+            id: 7,
+            password: "secure".into(),
+            maximum: 3.0,
+            secret: vec![42],
+        }
+    );
+}
+
+#[test]
+fn fields_with_methods() {
+    let lua = Lua::new();
+    lua.globals().set("player", Player { score: 1 }).unwrap();
+    lua.load("player:add_points(10); player.score = player.score * 2")
+        .exec()
+        .unwrap();
+    let player = lua.globals().get::<_, AnyUserData>("player").unwrap();
+    assert_eq!(player.borrow::<Player>().unwrap().score, 22);
+}